@@ -17,7 +17,7 @@ fn bouncing_ball() {
     let mut vel_x = 1;
     let mut vel_y = 1;
     let mut next_instant = Instant::now();
-    let style = Style { fg: Color::blue(), bg: Color::default(), attrs: Attrs::bold() };
+    let style = Style { fg: Color::blue(), attrs: Attrs::bold(), .. Style::default() };
     loop {
         select_widget! {
             () = time::sleep_until(next_instant) => {
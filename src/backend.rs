@@ -0,0 +1,428 @@
+//! Terminals that termcandy can draw to and read user-input from.
+use super::*;
+
+use futures::future;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::terminal::{
+    non_blocking_stdio, AlternateScreen, Blocking, MouseTerminal, NonBlockingStdin,
+    NonBlockingStdout, RawMode,
+};
+
+/// A terminal that a UI can be rendered to.
+///
+/// The backend is the output half of the terminal. It also hands out the input half (via
+/// `take_input`) which gets read from a separate task. `TtyBackend` is the default implementation
+/// which talks to the process's own stdin and stdout. Implement this trait to drive a UI over
+/// something else, such as a pty master, a socket or an in-memory buffer.
+pub trait Backend: AsyncWrite + Unpin {
+    /// The input half of the terminal.
+    type Input: AsyncRead + Unpin + Send + 'static;
+
+    /// Take the input half of the terminal. This is called once, before anything is drawn.
+    fn take_input(&mut self) -> io::Result<Self::Input>;
+
+    /// Get the current size of the terminal, as (width, height).
+    fn size(&self) -> io::Result<(u16, u16)>;
+
+    /// Get the size of the terminal in pixels, as (width, height), if it's known. This is used to
+    /// work out how big images need to be.
+    fn size_pixels(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Look up an environment variable which describes the terminal, such as `TERM` or
+    /// `COLORTERM`. These are used to guess which features the terminal supports.
+    ///
+    /// The default looks in the process's own environment. A backend for a terminal on the other
+    /// end of a connection should return the values from over there instead.
+    fn env_var(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+
+    /// Poll for the terminal being resized. Returns the new size.
+    fn poll_resize(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(u16, u16)>>;
+
+    /// Put the terminal into the modes needed to draw a UI. eg. raw mode, mouse reporting, the
+    /// alternate screen.
+    ///
+    /// If `inline` is true the UI is drawn inline with the terminal's other output, so the
+    /// alternate screen shouldn't be used, and neither should anything else which would stop the
+    /// user from scrolling back through that output.
+    ///
+    /// Calling this when the modes have already been entered should do nothing.
+    fn enter_modes(&mut self, inline: bool) -> io::Result<()>;
+
+    /// Restore the terminal to the way it was before `enter_modes` was called.
+    ///
+    /// This gets called from destructors, so it must not rely on being polled.
+    fn leave_modes(&mut self) -> io::Result<()>;
+
+    /// Write all of `buf` to the terminal, blocking if necessary.
+    ///
+    /// This gets called from destructors, when there's no longer an opportunity to poll.
+    fn write_blocking(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Hand the terminal over to another program, eg. an editor run with
+    /// `with_terminal_released`. This is called after `leave_modes`. Once the other program is
+    /// done with the terminal `reclaim` is called, followed by `enter_modes`.
+    ///
+    /// The default returns an error, for backends with no terminal that another program could
+    /// use. `with_terminal_released` then returns the error instead of calling its function.
+    fn release(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "this backend can't release the terminal"))
+    }
+
+    /// Take the terminal back after `release`.
+    fn reclaim(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Stop the process, the way a shell does when the user presses Ctrl-Z. The returned future
+    /// completes once it's been continued. This is called between `release` and `reclaim`.
+    ///
+    /// Backends which aren't the process's own terminal have nothing to stop. The default returns
+    /// an error, which `suspend` passes on.
+    fn suspend(&mut self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send>> {
+        let err = io::Error::new(io::ErrorKind::Other, "this backend can't suspend the process");
+        Box::pin(future::ready(Err(err)))
+    }
+}
+
+/// The default backend. Draws to the process's stdout and reads from its stdin.
+pub struct TtyBackend {
+    inner: AlternateScreen<MouseTerminal<RawMode<NonBlockingStdout>>>,
+    stdin_opt: Option<NonBlockingStdin>,
+    sigwinch: Signal,
+    released_opt: Option<Released>,
+}
+
+/// The state of a `TtyBackend` that's been released, which gets put back when it's reclaimed.
+struct Released {
+    _blocking: Blocking,
+    old_sigint: libc::sigaction,
+    old_sigquit: libc::sigaction,
+}
+
+impl TtyBackend {
+    /// Create a backend for the process's controlling terminal.
+    ///
+    /// This puts stdin and stdout into non-blocking mode for as long as the backend exists.
+    pub fn new() -> io::Result<TtyBackend> {
+        let (stdin, stdout) = non_blocking_stdio()?;
+        let inner = AlternateScreen::new(MouseTerminal::new(RawMode::new(stdout)));
+        let sigwinch = signal(SignalKind::window_change())?;
+        Ok(TtyBackend {
+            inner,
+            stdin_opt: Some(stdin),
+            sigwinch,
+            released_opt: None,
+        })
+    }
+}
+
+impl Backend for TtyBackend {
+    type Input = NonBlockingStdin;
+
+    fn take_input(&mut self) -> io::Result<NonBlockingStdin> {
+        match self.stdin_opt.take() {
+            Some(stdin) => Ok(stdin),
+            None => Err(io::Error::new(io::ErrorKind::Other, "stdin has already been taken")),
+        }
+    }
+
+    fn size(&self) -> io::Result<(u16, u16)> {
+        termion::terminal_size()
+    }
+
+    fn size_pixels(&self) -> Option<(u16, u16)> {
+        // Plenty of terminals leave the pixel size as zero.
+        termion::terminal_size_pixels().ok().filter(|&(w, h)| w > 0 && h > 0)
+    }
+
+    fn poll_resize(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(u16, u16)>> {
+        match Pin::new(&mut self.sigwinch).poll_next(cx) {
+            Poll::Ready(Some(())) => Poll::Ready(termion::terminal_size()),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn enter_modes(&mut self, inline: bool) -> io::Result<()> {
+        self.inner.get_mut().get_mut().enter()?;
+        if !inline {
+            // Mouse reporting would steal the scroll wheel from the terminal's scrollback.
+            self.inner.get_mut().enter()?;
+            self.inner.enter()?;
+        }
+        Ok(())
+    }
+
+    fn leave_modes(&mut self) -> io::Result<()> {
+        self.inner.leave()?;
+        self.inner.get_mut().leave()?;
+        self.inner.get_mut().get_mut().leave()?;
+        Ok(())
+    }
+
+    fn write_blocking(&mut self, buf: &[u8]) -> io::Result<()> {
+        let blocking = Blocking::new()?;
+        Write::write_all(&mut self.inner, buf)?;
+        drop(blocking);
+        Ok(())
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        if self.released_opt.is_some() {
+            return Ok(());
+        }
+        // Whoever gets the terminal shares our stdin and stdout, and won't be expecting them to
+        // be non-blocking.
+        let blocking = Blocking::new()?;
+        // Once raw mode is off, Ctrl-C and Ctrl-\ send signals to the whole process group. They're
+        // meant for the program using the terminal, not for us. A handler which does nothing
+        // (rather than SIG_IGN) is reset by exec, so child processes still get them.
+        let old_sigint = ignore_signal(libc::SIGINT)?;
+        let old_sigquit = match ignore_signal(libc::SIGQUIT) {
+            Ok(old_sigquit) => old_sigquit,
+            Err(err) => {
+                let _ = restore_signal_handler(libc::SIGINT, &old_sigint);
+                return Err(err);
+            },
+        };
+        self.released_opt = Some(Released { _blocking: blocking, old_sigint, old_sigquit });
+        Ok(())
+    }
+
+    fn reclaim(&mut self) -> io::Result<()> {
+        let released = match self.released_opt.take() {
+            Some(released) => released,
+            None => return Ok(()),
+        };
+        let sigint_res = restore_signal_handler(libc::SIGINT, &released.old_sigint);
+        let sigquit_res = restore_signal_handler(libc::SIGQUIT, &released.old_sigquit);
+        drop(released);
+        sigint_res?;
+        sigquit_res
+    }
+
+    fn suspend(&mut self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send>> {
+        // Stop the whole process group, as the terminal would have. The thread which sends the
+        // signal doesn't get any further until we get SIGCONT, so it can't be one of the runtime's
+        // workers.
+        let task = tokio::task::spawn_blocking(|| {
+            let res = unsafe { libc::kill(0, libc::SIGTSTP) };
+            if res != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        Box::pin(async move {
+            task.await.unwrap_or_else(|err| Err(io::Error::new(io::ErrorKind::Other, err)))
+        })
+    }
+}
+
+impl AsyncWrite for TtyBackend {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+extern "C" fn do_nothing(_signal: libc::c_int) {}
+
+/// Install a signal handler which does nothing, returning the old one.
+fn ignore_signal(signal: libc::c_int) -> io::Result<libc::sigaction> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = do_nothing as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut old_action: libc::sigaction = mem::zeroed();
+        if libc::sigaction(signal, &action, &mut old_action) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(old_action)
+    }
+}
+
+fn restore_signal_handler(signal: libc::c_int, old_action: &libc::sigaction) -> io::Result<()> {
+    let res = unsafe {
+        libc::sigaction(signal, old_action, std::ptr::null_mut())
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Something that a `MockBackend` has been asked to do.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MockCall {
+    EnterModes { inline: bool },
+    LeaveModes,
+    WriteBlocking,
+    Release,
+    Reclaim,
+    Suspend,
+}
+
+/// The state of a `MockBackend`, which the test keeps a handle to.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockState {
+    pub calls: Vec<MockCall>,
+    /// Everything written to the backend, whether by `poll_write` or `write_blocking`.
+    pub output: Vec<u8>,
+    pub size: (u16, u16),
+    /// Make `release` fail.
+    pub release_fails: bool,
+    /// The size that the terminal has been changed to once the process is continued after being
+    /// suspended. No resize gets reported for it, since the process was stopped at the time.
+    pub size_after_suspend_opt: Option<(u16, u16)>,
+    /// Make `poll_write` return `Pending`, as though the terminal were slow to read its input.
+    pub write_stalled: bool,
+    pub write_waker_opt: Option<Waker>,
+}
+
+#[cfg(test)]
+impl MockState {
+    pub fn unstall_writes(&mut self) {
+        self.write_stalled = false;
+        if let Some(waker) = self.write_waker_opt.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A backend which records what it's asked to do instead of talking to a terminal.
+#[cfg(test)]
+pub(crate) struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn new(w: u16, h: u16) -> MockBackend {
+        let state = MockState { size: (w, h), ..MockState::default() };
+        MockBackend {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn state(&self) -> Arc<Mutex<MockState>> {
+        self.state.clone()
+    }
+
+    fn record(&self, call: MockCall) {
+        self.state.lock().unwrap().calls.push(call);
+    }
+}
+
+#[cfg(test)]
+impl Backend for MockBackend {
+    type Input = tokio::io::Empty;
+
+    fn take_input(&mut self) -> io::Result<tokio::io::Empty> {
+        Ok(tokio::io::empty())
+    }
+
+    fn size(&self) -> io::Result<(u16, u16)> {
+        Ok(self.state.lock().unwrap().size)
+    }
+
+    fn env_var(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn poll_resize(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<(u16, u16)>> {
+        Poll::Pending
+    }
+
+    fn enter_modes(&mut self, inline: bool) -> io::Result<()> {
+        self.record(MockCall::EnterModes { inline });
+        Ok(())
+    }
+
+    fn leave_modes(&mut self) -> io::Result<()> {
+        self.record(MockCall::LeaveModes);
+        Ok(())
+    }
+
+    fn write_blocking(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(MockCall::WriteBlocking);
+        state.output.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        self.record(MockCall::Release);
+        if self.state.lock().unwrap().release_fails {
+            return Err(io::Error::new(io::ErrorKind::Other, "release failed"));
+        }
+        Ok(())
+    }
+
+    fn reclaim(&mut self) -> io::Result<()> {
+        self.record(MockCall::Reclaim);
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send>> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(MockCall::Suspend);
+        if let Some(size) = state.size_after_suspend_opt.take() {
+            state.size = size;
+        }
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+#[cfg(test)]
+impl AsyncWrite for MockBackend {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.write_stalled {
+            state.write_waker_opt = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        state.output.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,71 @@
+//! Base64, as used by the escape sequences which carry binary data.
+
+const ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode base64, ignoring padding. Returns `None` if the data isn't valid base64.
+pub fn decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+    let mut n = 0u32;
+    let mut bits = 0;
+    for &c in data.iter().take_while(|&&c| c != b'=') {
+        let value = ALPHABET.iter().position(|&a| a == c)?;
+        n = n << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The test vectors from RFC 4648.
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encode_vectors() {
+        for &(bytes, encoded) in &VECTORS {
+            assert_eq!(encode(bytes.as_bytes()), encoded);
+        }
+        assert_eq!(encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    fn decode_vectors() {
+        for &(bytes, encoded) in &VECTORS {
+            assert_eq!(decode(encoded.as_bytes()).unwrap(), bytes.as_bytes());
+        }
+        assert_eq!(decode(b"+/+/").unwrap(), [0xfb, 0xff, 0xbf]);
+        assert_eq!(decode(b"Zm9v\n"), None);
+        assert_eq!(decode(b"Zm9v-"), None);
+    }
+}
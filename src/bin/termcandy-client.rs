@@ -0,0 +1,21 @@
+//! Connects the terminal to a termcandy UI served with `termcandy::server::serve`.
+//!
+//! Usage: `termcandy-client <socket path>`
+
+use std::{env, process};
+
+#[tokio::main]
+async fn main() {
+    let mut args = env::args_os().skip(1);
+    let path = match (args.next(), args.next()) {
+        (Some(path), None) => path,
+        _ => {
+            eprintln!("usage: termcandy-client <socket path>");
+            process::exit(2);
+        },
+    };
+    if let Err(err) = termcandy::server::connect(&path).await {
+        eprintln!("termcandy-client: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,80 @@
+//! Reading and writing the system clipboard through the terminal.
+//!
+//! This uses OSC 52, which has the terminal emulator itself access the clipboard. That means it
+//! works over SSH and inside tmux, with no help from the machine the program runs on. Not every
+//! terminal supports it, and many which do only allow writing, or ask the user before letting a
+//! program read the clipboard.
+
+use super::*;
+
+use crate::base64;
+use crate::input::{Reply, expect_reply, wait_for_reply};
+use crate::screen::write_sequence;
+
+/// How long to wait for the terminal to send the clipboard contents. This is generous since some
+/// terminals ask the user for permission first.
+const GET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which selection to access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// The clipboard, ie. what copy and paste use.
+    Clipboard,
+    /// The primary selection: the text most recently selected, which is pasted with a middle
+    /// click on X11.
+    Primary,
+}
+
+impl Selection {
+    fn osc_param(self) -> char {
+        match self {
+            Selection::Clipboard => 'c',
+            Selection::Primary => 'p',
+        }
+    }
+}
+
+/// Copy text to the clipboard.
+pub fn set(text: &str) {
+    set_selection(Selection::Clipboard, text)
+}
+
+/// Copy text to the given selection.
+///
+/// Like everything else the UI draws, this is sent to the terminal on the next frame. It does
+/// nothing outside of `termcandy::run`.
+pub fn set_selection(selection: Selection, text: &str) {
+    let data = base64::encode(text.as_bytes());
+    let sequence = format!("\x1b]52;{};{}\x1b\\", selection.osc_param(), data);
+    write_sequence(sequence.into_bytes());
+}
+
+/// Get the contents of the clipboard.
+pub async fn get() -> Option<String> {
+    get_selection(Selection::Clipboard).await
+}
+
+/// Get the contents of the given selection.
+///
+/// Returns `None` if the terminal doesn't answer, which is what happens if it doesn't support
+/// reading the clipboard or the user refuses permission. This can take a few seconds. It also
+/// returns `None` immediately outside of `termcandy::run`.
+pub async fn get_selection(selection: Selection) -> Option<String> {
+    let filter = Box::new(|reply: &Reply| match reply {
+        Reply::Osc(osc) => osc.starts_with(b"52;"),
+        _ => false,
+    });
+    let receiver = expect_reply(filter)?;
+    let query = format!("\x1b]52;{};?\x1b\\", selection.osc_param());
+    if !write_sequence(query.into_bytes()) {
+        return None;
+    }
+    let osc = match wait_for_reply(receiver, GET_TIMEOUT).await? {
+        Reply::Osc(osc) => osc,
+        _ => return None,
+    };
+    // The reply is `52;<selection>;<base64 data>`.
+    let data = osc.splitn(3, |&b| b == b';').nth(2)?;
+    let bytes = base64::decode(data)?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
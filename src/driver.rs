@@ -0,0 +1,127 @@
+use super::*;
+
+use std::collections::VecDeque;
+use futures::future;
+
+use crate::graphics::SurfaceMut;
+use crate::input::{Event, SharedWatcherSet, WatcherSet};
+use crate::widget::Widget;
+
+/// Drives a widget from a foreign event loop.
+///
+/// `run` owns the terminal: it reads input, watches for resizes and renders to the screen itself.
+/// A `Driver` instead leaves all of that to the caller, which makes it possible to host a widget
+/// inside an application which already owns the terminal. The caller feeds it input events and
+/// size changes, polls it whenever it's woken, and renders it to a surface of its choosing.
+pub struct Driver<W: Widget> {
+    widget: Pin<Box<W>>,
+    complete: bool,
+    watcher_set: SharedWatcherSet,
+    pending_events: VecDeque<Event>,
+    w: u16,
+    h: u16,
+    waker_opt: Option<Waker>,
+}
+
+impl<W: Widget> Driver<W> {
+    /// Create a driver for a widget occupying an area of the given size.
+    pub fn new(widget: W, w: u16, h: u16) -> Driver<W> {
+        Driver {
+            widget: Box::pin(widget),
+            complete: false,
+            watcher_set: WatcherSet::new_shared(),
+            pending_events: VecDeque::new(),
+            w,
+            h,
+            waker_opt: None,
+        }
+    }
+
+    /// Queue an input event for the widget. The event is delivered on the next call to `poll`.
+    pub fn feed_event(&mut self, event: Event) {
+        self.pending_events.push_back(event);
+        if let Some(waker) = self.waker_opt.take() {
+            waker.wake();
+        }
+    }
+
+    /// Set the size of the area that the widget occupies. This is what `screen_size()` will report
+    /// from inside the widget.
+    pub fn set_size(&mut self, w: u16, h: u16) {
+        self.w = w;
+        self.h = h;
+        if let Some(waker) = self.waker_opt.take() {
+            waker.wake();
+        }
+    }
+
+    /// Get the size of the area that the widget occupies.
+    pub fn size(&self) -> (u16, u16) {
+        (self.w, self.h)
+    }
+
+    /// Poll the widget, delivering any queued input events to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called again after the widget has completed.
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<W::Output> {
+        assert!(!self.complete, "Driver polled after widget completed");
+        self.waker_opt = Some(cx.waker().clone());
+        loop {
+            let widget = self.widget.as_mut();
+            let poll = in_session(&self.watcher_set, self.w, self.h, cx, |cx| widget.poll(cx));
+            if let Poll::Ready(output) = poll {
+                self.complete = true;
+                return Poll::Ready(output);
+            }
+
+            // Once every watcher has seen the last event, hand out the next one.
+            let mut watcher_set_opt = self.watcher_set.lock().unwrap();
+            let set = watcher_set_opt.as_mut().unwrap();
+            if !set.all_polled() {
+                return Poll::Pending;
+            }
+            match self.pending_events.pop_front() {
+                Some(event) => set.dispatch(Poll::Ready(Some(event))),
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Whether the widget has completed.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Clear the surface then draw the widget to it.
+    pub fn render(&self, surface: &mut SurfaceMut) {
+        let widget = self.widget.as_ref().get_ref();
+        surface.clear();
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        in_session(&self.watcher_set, self.w, self.h, &mut cx, |_| widget.draw(surface));
+    }
+}
+
+/// Call `func` with the task-locals that a widget expects to be set.
+fn in_session<R>(
+    watcher_set: &SharedWatcherSet,
+    w: u16,
+    h: u16,
+    cx: &mut Context<'_>,
+    func: impl FnOnce(&mut Context<'_>) -> R,
+) -> R {
+    let mut func_opt = Some(func);
+    let future = crate::input::with_watcher_set(
+        watcher_set.clone(),
+        crate::screen::with_screen_size_scope(w, h, future::poll_fn(|cx| {
+            let func = func_opt.take().unwrap();
+            Poll::Ready(func(cx))
+        })),
+    );
+    pin_utils::pin_mut!(future);
+    match future.poll(cx) {
+        Poll::Ready(ret) => ret,
+        Poll::Pending => unreachable!(),
+    }
+}
@@ -1,5 +1,5 @@
 /// A color
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Color {
     /// Default terminal color. eg. use this to set transparent background.
     Default,
@@ -92,12 +92,124 @@ impl Color {
     pub fn rgb(r: u8, g: u8, b: u8) -> Color {
         Color::Rgb { r, b, g }
     }
+
+    /// The RGB value of the color, or `None` for `Color::Default`. Palette colors are given the
+    /// values of xterm's default palette, though terminals may display them differently.
+    pub fn to_rgb(self) -> Option<(u8, u8, u8)> {
+        match self {
+            Color::Default => None,
+            Color::Colors16 { code, bright } => {
+                Some(palette_rgb(code as u8 + if bright { 8 } else { 0 }))
+            },
+            Color::Colors256(index) => Some(palette_rgb(index)),
+            Color::Rgb { r, g, b } => Some((r, g, b)),
+        }
+    }
+
+    /// Whether the color is dark, ie. closer to black than to white by perceived brightness. `None`
+    /// for `Color::Default`, since it depends on the terminal.
+    pub fn is_dark(self) -> Option<bool> {
+        let (r, g, b) = self.to_rgb()?;
+        let brightness = 299 * r as u32 + 587 * g as u32 + 114 * b as u32;
+        Some(brightness < 128 * 1000)
+    }
+
+    /// Convert the color to the closest one that can be displayed with the given color depth.
+    ///
+    /// Colors which already fit are returned unchanged. Otherwise the nearest entry of the
+    /// standard xterm palette is chosen, using a distance weighted to roughly match how the eye
+    /// perceives differences in color.
+    pub fn downsample(self, depth: ColorDepth) -> Color {
+        match (self, depth) {
+            (Color::Default, _) |
+            (Color::Colors16 { .. }, _) |
+            (_, ColorDepth::TrueColor) |
+            (Color::Colors256(_), ColorDepth::Colors256) => self,
+            (Color::Rgb { r, g, b }, ColorDepth::Colors256) => {
+                let index = (16..=255u8)
+                    .min_by_key(|&index| color_distance((r, g, b), palette_rgb(index)))
+                    .unwrap();
+                Color::Colors256(index)
+            },
+            (Color::Colors256(index), ColorDepth::Colors16) if index < 16 => {
+                colors16_from_index(index)
+            },
+            (Color::Colors256(index), ColorDepth::Colors16) => {
+                nearest_colors16(palette_rgb(index))
+            },
+            (Color::Rgb { r, g, b }, ColorDepth::Colors16) => nearest_colors16((r, g, b)),
+        }
+    }
+}
+
+/// How many colors a terminal can display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ColorDepth {
+    /// Only the 16 basic colors.
+    Colors16,
+    /// The 256-color xterm palette.
+    Colors256,
+    /// 24-bit color.
+    TrueColor,
+}
+
+/// The RGB values of the xterm palette entry `index`. The first 16 entries are xterm's defaults,
+/// though most terminals let the user change them.
+fn palette_rgb(index: u8) -> (u8, u8, u8) {
+    const COLORS16: [(u8, u8, u8); 16] = [
+        (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+        (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+        (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+        (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => COLORS16[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            let level = |n: u8| CUBE_LEVELS[n as usize];
+            (level(i / 36), level(i / 6 % 6), level(i % 6))
+        },
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        },
+    }
+}
+
+fn colors16_from_index(index: u8) -> Color {
+    let code = match index % 8 {
+        0 => ColorCode::Black,
+        1 => ColorCode::Red,
+        2 => ColorCode::Green,
+        3 => ColorCode::Yellow,
+        4 => ColorCode::Blue,
+        5 => ColorCode::Magenta,
+        6 => ColorCode::Cyan,
+        _ => ColorCode::White,
+    };
+    Color::Colors16 { code, bright: index >= 8 }
+}
+
+fn nearest_colors16(rgb: (u8, u8, u8)) -> Color {
+    let index = (0..16u8).min_by_key(|&index| color_distance(rgb, palette_rgb(index))).unwrap();
+    colors16_from_index(index)
+}
+
+/// The "redmean" approximation of perceptual distance between two colors. It's much cheaper
+/// than converting to a proper perceptual color space and does a decent job.
+fn color_distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let r_mean = (a.0 as i32 + b.0 as i32) / 2;
+    let dr = a.0 as i32 - b.0 as i32;
+    let dg = a.1 as i32 - b.1 as i32;
+    let db = a.2 as i32 - b.2 as i32;
+    ((((512 + r_mean) * dr * dr) >> 8) + 4 * dg * dg + (((767 - r_mean) * db * db) >> 8)) as u32
 }
 
 /// A simple 3-bit color code.
 ///
 /// These colors should be compatible with just about any terminal under the sun.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ColorCode {
     Black = 0,
     Red = 1,
@@ -109,3 +221,93 @@ pub enum ColorCode {
     White = 7,
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn is_dark() {
+        assert_eq!(Color::Default.is_dark(), None);
+        assert_eq!(Color::rgb(0, 0, 0).is_dark(), Some(true));
+        assert_eq!(Color::rgb(255, 255, 255).is_dark(), Some(false));
+        // Blue counts for much less than green.
+        assert_eq!(Color::rgb(0, 0, 255).is_dark(), Some(true));
+        assert_eq!(Color::rgb(0, 255, 0).is_dark(), Some(false));
+        // Either side of the threshold: 128 * (299 + 587 + 114) = 128000.
+        assert_eq!(Color::rgb(128, 128, 128).is_dark(), Some(false));
+        assert_eq!(Color::rgb(127, 127, 127).is_dark(), Some(true));
+        let colors16 = |code, bright| Color::Colors16 { code, bright };
+        assert_eq!(colors16(ColorCode::Blue, false).is_dark(), Some(true));
+        assert_eq!(colors16(ColorCode::White, true).is_dark(), Some(false));
+        // 232 to 255 are a greyscale ramp, from dark to light.
+        assert_eq!(Color::Colors256(232).is_dark(), Some(true));
+        assert_eq!(Color::Colors256(255).is_dark(), Some(false));
+    }
+
+    #[test]
+    fn palette() {
+        assert_eq!(palette_rgb(0), (0, 0, 0));
+        assert_eq!(palette_rgb(15), (255, 255, 255));
+        // The 6x6x6 cube runs from 16 to 231, with blue varying fastest.
+        assert_eq!(palette_rgb(16), (0, 0, 0));
+        assert_eq!(palette_rgb(17), (0, 0, 95));
+        assert_eq!(palette_rgb(22), (0, 95, 0));
+        assert_eq!(palette_rgb(52), (95, 0, 0));
+        assert_eq!(palette_rgb(231), (255, 255, 255));
+        // Then the greyscale ramp, which stops short of black and white.
+        assert_eq!(palette_rgb(232), (8, 8, 8));
+        assert_eq!(palette_rgb(255), (238, 238, 238));
+    }
+
+    #[test]
+    fn downsample_to_256() {
+        let to_256 = |color: Color| color.downsample(ColorDepth::Colors256);
+        // Exact matches in the cube.
+        assert_eq!(to_256(Color::rgb(255, 0, 0)), Color::Colors256(196));
+        assert_eq!(to_256(Color::rgb(95, 135, 175)), Color::Colors256(67));
+        assert_eq!(to_256(Color::rgb(0, 0, 0)), Color::Colors256(16));
+        // Greys mostly fall between the levels of the cube, but not of the ramp.
+        assert_eq!(to_256(Color::rgb(128, 128, 128)), Color::Colors256(244));
+        assert_eq!(to_256(Color::rgb(30, 30, 30)), Color::Colors256(234));
+        // The closest grey to a dull color can be nearer than anything in the cube.
+        assert_eq!(to_256(Color::rgb(100, 96, 96)), Color::Colors256(241));
+        // Off-cube colors go to the nearest level.
+        assert_eq!(to_256(Color::rgb(250, 10, 5)), Color::Colors256(196));
+        // The first 16 entries aren't used, since terminals don't agree on what they look like.
+        assert_eq!(to_256(Color::rgb(205, 0, 0)), Color::Colors256(160));
+    }
+
+    #[test]
+    fn downsample_to_16() {
+        let to_16 = |color: Color| color.downsample(ColorDepth::Colors16);
+        assert_eq!(to_16(Color::Colors256(1)), Color::red());
+        assert_eq!(to_16(Color::Colors256(9)), Color::bright_red());
+        assert_eq!(to_16(Color::Colors256(196)), Color::bright_red());
+        assert_eq!(to_16(Color::Colors256(244)), Color::bright_black());
+        assert_eq!(to_16(Color::Colors256(16)), Color::black());
+        assert_eq!(to_16(Color::Colors256(255)), Color::white());
+        assert_eq!(to_16(Color::rgb(200, 0, 0)), Color::red());
+        assert_eq!(to_16(Color::rgb(250, 250, 250)), Color::bright_white());
+        assert_eq!(to_16(Color::rgb(0, 0, 200)), Color::blue());
+        assert_eq!(to_16(Color::rgb(90, 90, 250)), Color::bright_blue());
+    }
+
+    #[test]
+    fn downsample_unchanged() {
+        let colors = [
+            Color::Default,
+            Color::bright_cyan(),
+            Color::Colors256(67),
+            Color::rgb(1, 2, 3),
+        ];
+        for &color in &colors {
+            assert_eq!(color.downsample(ColorDepth::TrueColor), color);
+        }
+        assert_eq!(Color::Colors256(67).downsample(ColorDepth::Colors256), Color::Colors256(67));
+        for &depth in &[ColorDepth::Colors16, ColorDepth::Colors256] {
+            assert_eq!(Color::Default.downsample(depth), Color::Default);
+            assert_eq!(Color::bright_cyan().downsample(depth), Color::bright_cyan());
+        }
+    }
+}
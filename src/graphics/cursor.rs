@@ -0,0 +1,62 @@
+/// The style of the terminal's cursor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CursorStyle {
+    pub kind: CursorKind,
+    pub blink: bool,
+}
+
+impl CursorStyle {
+    pub fn block() -> CursorStyle {
+        CursorStyle {
+            kind: CursorKind::Block,
+            blink: false,
+        }
+    }
+
+    pub fn underline() -> CursorStyle {
+        CursorStyle {
+            kind: CursorKind::Underline,
+            blink: false,
+        }
+    }
+
+    pub fn bar() -> CursorStyle {
+        CursorStyle {
+            kind: CursorKind::Bar,
+            blink: false,
+        }
+    }
+
+    /// Make the cursor blink.
+    pub fn blinking(self) -> CursorStyle {
+        CursorStyle {
+            blink: true,
+            .. self
+        }
+    }
+}
+
+impl Default for CursorStyle {
+    fn default() -> CursorStyle {
+        CursorStyle::block()
+    }
+}
+
+/// The shape of the cursor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CursorKind {
+    /// Covers the whole cell.
+    Block,
+    /// A line along the bottom of the cell.
+    Underline,
+    /// A vertical line along the left edge of the cell, for showing where text will be inserted.
+    Bar,
+}
+
+/// Where a surface wants the cursor, and what it should look like.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    pub x: u16,
+    pub y: u16,
+    pub style: CursorStyle,
+}
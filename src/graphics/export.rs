@@ -0,0 +1,383 @@
+//! Converting the contents of a surface into other formats, eg. for documentation, bug reports
+//! and snapshot diffs.
+use super::*;
+
+use crate::screen::{link_transition, sgr_transition};
+
+/// The width of a cell in exported SVGs, in pixels.
+const SVG_CELL_WIDTH: u32 = 9;
+/// The height of a cell in exported SVGs, in pixels.
+const SVG_CELL_HEIGHT: u32 = 18;
+const SVG_FONT_SIZE: u32 = 15;
+/// The colors used for `Color::Default` in exported SVGs, which have no terminal to inherit from.
+const SVG_DEFAULT_FG: (u8, u8, u8) = (229, 229, 229);
+const SVG_DEFAULT_BG: (u8, u8, u8) = (0, 0, 0);
+
+/// A run of consecutive cells in a row which all have the same style.
+struct StyledRun {
+    x: u16,
+    width: u16,
+    style: Style,
+    /// The characters in the run, with the column each one starts at. The cells covered by the
+    /// right-hand half of a wide character are left out.
+    chars: Vec<(u16, Grapheme)>,
+}
+
+impl StyledRun {
+    fn text(&self) -> String {
+        self.chars.iter().map(|(_, c)| c.as_str()).collect()
+    }
+}
+
+fn styled_runs(surface: &SurfaceRef, y: u16) -> Vec<StyledRun> {
+    let mut runs: Vec<StyledRun> = Vec::new();
+    for x in 0..surface.width() {
+        let cell = surface.cell(x, y);
+        let continues = match runs.last() {
+            Some(run) => run.style == cell.style,
+            None => false,
+        };
+        if !continues {
+            runs.push(StyledRun { x, width: 0, style: cell.style, chars: Vec::new() });
+        }
+        let run = runs.last_mut().unwrap();
+        run.width += 1;
+        if cell.c != '\0' {
+            run.chars.push((x, cell.c));
+        }
+    }
+    runs
+}
+
+impl<'a> SurfaceRef<'a> {
+    /// Convert the surface to text containing ANSI escape sequences, one line per row.
+    ///
+    /// Printing the result to a terminal reproduces the surface, as long as the terminal
+    /// supports all the colors and attributes used. Trailing blank cells are left off each line.
+    pub fn to_ansi(&self) -> String {
+        let mut out = String::new();
+        for y in 0..self.height() {
+            let mut runs = styled_runs(self, y);
+            if let Some(run) = runs.last_mut() {
+                if run.style == Style::default() {
+                    let len = run.chars.iter().rposition(|&(_, c)| c != ' ').map_or(0, |i| i + 1);
+                    run.chars.truncate(len);
+                }
+            }
+            let mut style = Style::default();
+            for run in runs {
+                out.push_str(&sgr_transition(style, run.style));
+                out.push_str(&link_transition(style.link, run.style.link));
+                out.push_str(&run.text());
+                style = run.style;
+            }
+            out.push_str(&sgr_transition(style, Style::default()));
+            out.push_str(&link_transition(style.link, None));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Convert the surface to an HTML `<pre>` element, with each run of styled text in a `<span>`
+    /// with inline CSS.
+    ///
+    /// `Color::Default` is left to be inherited from the surrounding page. Links become `<a>`
+    /// elements.
+    pub fn to_html(&self) -> String {
+        let mut out = String::from("<pre class=\"termcandy\">");
+        for y in 0..self.height() {
+            for run in styled_runs(self, y) {
+                if let Some(link) = run.style.link {
+                    out.push_str("<a href=\"");
+                    push_escaped_xml(&mut out, link.url());
+                    out.push_str("\">");
+                }
+                let css = style_css(run.style);
+                if css.is_empty() {
+                    push_escaped_xml(&mut out, &run.text());
+                } else {
+                    out.push_str(&format!("<span style=\"{}\">", css));
+                    push_escaped_xml(&mut out, &run.text());
+                    out.push_str("</span>");
+                }
+                if run.style.link.is_some() {
+                    out.push_str("</a>");
+                }
+            }
+            out.push('\n');
+        }
+        out.push_str("</pre>\n");
+        out
+    }
+
+    /// Convert the surface to an SVG image, drawn in a monospace font on a grid of cells.
+    ///
+    /// Each grapheme is positioned on the grid individually, so wide characters and fonts
+    /// without the exact right glyph width don't throw the layout off. Since there's no terminal
+    /// theme to inherit from, `Color::Default` is drawn as light gray text on a black background.
+    pub fn to_svg(&self) -> String {
+        let image_w = self.width() as u32 * SVG_CELL_WIDTH;
+        let image_h = self.height() as u32 * SVG_CELL_HEIGHT;
+        let mut out = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+             font-family=\"monospace\" font-size=\"{}\">\n",
+            image_w, image_h, SVG_FONT_SIZE,
+        );
+        out.push_str(&format!(
+            "<rect width=\"{}\" height=\"{}\" fill=\"{}\"/>\n",
+            image_w, image_h, hex_color(SVG_DEFAULT_BG),
+        ));
+        for y in 0..self.height() {
+            let top = y as u32 * SVG_CELL_HEIGHT;
+            let baseline = top + SVG_CELL_HEIGHT * 3 / 4;
+            for run in styled_runs(self, y) {
+                let left = run.x as u32 * SVG_CELL_WIDTH;
+                let width = run.width as u32 * SVG_CELL_WIDTH;
+                if let Some(bg) = run.style.bg.to_rgb() {
+                    out.push_str(&format!(
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>\n",
+                        left, top, width, SVG_CELL_HEIGHT, hex_color(bg),
+                    ));
+                }
+
+                let fg = hex_color(run.style.fg.to_rgb().unwrap_or(SVG_DEFAULT_FG));
+                let chars: Vec<(u16, Grapheme)> = {
+                    run.chars.iter().cloned().filter(|&(_, c)| c != ' ').collect()
+                };
+                if !chars.is_empty() {
+                    if let Some(link) = run.style.link {
+                        out.push_str("<a href=\"");
+                        push_escaped_xml(&mut out, link.url());
+                        out.push_str("\">");
+                    }
+                    out.push_str(&format!(
+                        "<text y=\"{}\" fill=\"{}\"{}>",
+                        baseline, fg, svg_text_attrs(run.style.attrs),
+                    ));
+                    // One x per grapheme rather than a single list for the whole run, since
+                    // renderers disagree on how many positions a multi-code-point grapheme uses.
+                    for &(x, c) in &chars {
+                        out.push_str(&format!("<tspan x=\"{}\">", x as u32 * SVG_CELL_WIDTH));
+                        push_escaped_xml(&mut out, c.as_str());
+                        out.push_str("</tspan>");
+                    }
+                    out.push_str("</text>");
+                    if run.style.link.is_some() {
+                        out.push_str("</a>");
+                    }
+                    out.push('\n');
+                }
+
+                push_svg_lines(&mut out, &run, &fg, top, left, width);
+            }
+        }
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+impl Surface {
+    /// Convert the surface to text containing ANSI escape sequences. See `SurfaceRef::to_ansi`.
+    pub fn to_ansi(&self) -> String {
+        self.as_ref().to_ansi()
+    }
+
+    /// Convert the surface to an HTML `<pre>` element. See `SurfaceRef::to_html`.
+    pub fn to_html(&self) -> String {
+        self.as_ref().to_html()
+    }
+
+    /// Convert the surface to an SVG image. See `SurfaceRef::to_svg`.
+    pub fn to_svg(&self) -> String {
+        self.as_ref().to_svg()
+    }
+}
+
+fn hex_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn push_escaped_xml(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn style_css(style: Style) -> String {
+    let mut props = Vec::new();
+    if let Some(fg) = style.fg.to_rgb() {
+        props.push(format!("color:{}", hex_color(fg)));
+    }
+    if let Some(bg) = style.bg.to_rgb() {
+        props.push(format!("background-color:{}", hex_color(bg)));
+    }
+    let attrs = style.attrs;
+    if attrs.bold {
+        props.push(String::from("font-weight:bold"));
+    }
+    if attrs.faint {
+        props.push(String::from("opacity:0.6"));
+    }
+    if attrs.italic {
+        props.push(String::from("font-style:italic"));
+    }
+
+    let mut lines = Vec::new();
+    if attrs.underline.is_some() {
+        lines.push("underline");
+    }
+    if attrs.strikethrough {
+        lines.push("line-through");
+    }
+    if attrs.overlined {
+        lines.push("overline");
+    }
+    if attrs.blink {
+        lines.push("blink");
+    }
+    if !lines.is_empty() {
+        props.push(format!("text-decoration-line:{}", lines.join(" ")));
+    }
+    if let Some(underline) = attrs.underline {
+        match underline.kind {
+            UnderlineKind::Single => (),
+            UnderlineKind::Double => props.push(String::from("text-decoration-style:double")),
+            UnderlineKind::Wavy => props.push(String::from("text-decoration-style:wavy")),
+        }
+        if let Some(color) = underline.color.to_rgb() {
+            props.push(format!("text-decoration-color:{}", hex_color(color)));
+        }
+    }
+    props.join(";")
+}
+
+fn svg_text_attrs(attrs: Attrs) -> String {
+    let mut out = String::new();
+    if attrs.bold {
+        out.push_str(" font-weight=\"bold\"");
+    }
+    if attrs.faint {
+        out.push_str(" opacity=\"0.6\"");
+    }
+    if attrs.italic {
+        out.push_str(" font-style=\"italic\"");
+    }
+    out
+}
+
+/// Draw the underline, strikethrough and overline of a run. These are drawn by hand rather than
+/// with `text-decoration`, which SVG renderers support patchily.
+fn push_svg_lines(out: &mut String, run: &StyledRun, fg: &str, top: u32, left: u32, width: u32) {
+    let attrs = run.style.attrs;
+    let right = left + width;
+    let mut push_line = |y: u32, color: &str| {
+        out.push_str(&format!(
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\"/>\n",
+            left, y, right, y, color,
+        ));
+    };
+    if attrs.overlined {
+        push_line(top + 1, fg);
+    }
+    if attrs.strikethrough {
+        push_line(top + SVG_CELL_HEIGHT / 2, fg);
+    }
+    let underline = match attrs.underline {
+        Some(underline) => underline,
+        None => return,
+    };
+    let color = match underline.color.to_rgb() {
+        Some(color) => hex_color(color),
+        None => String::from(fg),
+    };
+    let y = top + SVG_CELL_HEIGHT - 3;
+    match underline.kind {
+        UnderlineKind::Single => push_line(y, &color),
+        UnderlineKind::Double => {
+            push_line(y - 1, &color);
+            push_line(y + 1, &color);
+        },
+        UnderlineKind::Wavy => {
+            let mut points = Vec::new();
+            let mut x = left;
+            let mut up = true;
+            while x <= right {
+                points.push(format!("{},{}", x, if up { y - 1 } else { y + 1 }));
+                x += 2;
+                up = !up;
+            }
+            out.push_str(&format!(
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\"/>\n",
+                points.join(" "), color,
+            ));
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> Surface {
+        let mut surface = Surface::blank(5, 2);
+        surface.print("a", 0, 0, Style { fg: Color::red(), ..Style::bold() });
+        surface.print("b<", 1, 0, Style::link(Link::new("https://example.com/?a&b")));
+        surface.print("\u{4e16}", 0, 1, Style::underline(UnderlineStyle::single()));
+        surface
+    }
+
+    #[test]
+    fn ansi() {
+        assert_eq!(
+            sample().to_ansi(),
+            "\x1b[31;1ma\x1b[m\x1b]8;;https://example.com/?a&b\x1b\\b<\x1b]8;;\x1b\\\n\
+             \x1b[4m\u{4e16}\x1b[m\n",
+        );
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            sample().to_html(),
+            "<pre class=\"termcandy\">\
+             <span style=\"color:#cd0000;font-weight:bold\">a</span>\
+             <a href=\"https://example.com/?a&amp;b\">b&lt;</a>  \n\
+             <span style=\"text-decoration-line:underline\">\u{4e16}</span>   \n\
+             </pre>\n",
+        );
+    }
+
+    #[test]
+    fn svg() {
+        let expected = concat!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"45\" height=\"36\" ",
+            "font-family=\"monospace\" font-size=\"15\">\n",
+            "<rect width=\"45\" height=\"36\" fill=\"#000000\"/>\n",
+            "<text y=\"13\" fill=\"#cd0000\" font-weight=\"bold\">",
+            "<tspan x=\"0\">a</tspan></text>\n",
+            "<a href=\"https://example.com/?a&amp;b\"><text y=\"13\" fill=\"#e5e5e5\">",
+            "<tspan x=\"9\">b</tspan><tspan x=\"18\">&lt;</tspan></text></a>\n",
+            "<text y=\"31\" fill=\"#e5e5e5\"><tspan x=\"0\">\u{4e16}</tspan></text>\n",
+            "<line x1=\"0\" y1=\"33\" x2=\"18\" y2=\"33\" stroke=\"#e5e5e5\"/>\n",
+            "</svg>\n",
+        );
+        assert_eq!(sample().to_svg(), expected);
+    }
+
+    #[test]
+    fn svg_positions_each_grapheme() {
+        let mut surface = Surface::blank(3, 1);
+        surface.print("e\u{301}xy", 0, 0, Style::default());
+        assert!(surface.to_svg().contains(
+            "<text y=\"13\" fill=\"#e5e5e5\">\
+             <tspan x=\"0\">e\u{301}</tspan><tspan x=\"9\">x</tspan><tspan x=\"18\">y</tspan>\
+             </text>\n",
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+use super::*;
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Graphemes up to this many bytes long are stored inline. Longer ones are interned.
+const INLINE_CAPACITY: usize = 22;
+
+lazy_static! {
+    static ref INTERNED: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// An extended grapheme cluster: what the user sees as a single character, eg. a letter along
+/// with its combining accents, a flag, or an emoji built out of several others with zero-width
+/// joiners. This is what a cell of a surface holds.
+///
+/// Most graphemes are stored inline. Unusually long ones are interned, and live for the rest of
+/// the program.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Grapheme {
+    repr: Repr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Repr {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_CAPACITY],
+    },
+    Interned(&'static str),
+}
+
+impl Grapheme {
+    /// Create a grapheme from a string. The string should be a single grapheme cluster, eg. one
+    /// of the items returned by `UnicodeSegmentation::graphemes`.
+    pub fn new(s: &str) -> Grapheme {
+        if s.len() <= INLINE_CAPACITY {
+            let mut bytes = [0; INLINE_CAPACITY];
+            bytes[..s.len()].copy_from_slice(s.as_bytes());
+            return Grapheme { repr: Repr::Inline { len: s.len() as u8, bytes } };
+        }
+        let mut interned = INTERNED.lock().unwrap();
+        let s = match interned.get(s) {
+            Some(s) => *s,
+            None => {
+                let s: &'static str = Box::leak(s.to_owned().into_boxed_str());
+                interned.insert(s);
+                s
+            },
+        };
+        Grapheme { repr: Repr::Interned(s) }
+    }
+
+    /// Create a grapheme from a single character.
+    pub fn from_char(c: char) -> Grapheme {
+        let mut buf = [0; 4];
+        Grapheme::new(c.encode_utf8(&mut buf))
+    }
+
+    /// Get the grapheme as a string.
+    pub fn as_str(&self) -> &str {
+        match &self.repr {
+            Repr::Inline { len, bytes } => {
+                std::str::from_utf8(&bytes[..(*len as usize)]).unwrap()
+            },
+            Repr::Interned(s) => s,
+        }
+    }
+
+    /// Get the grapheme as a `char`, if it's made up of just one.
+    pub fn as_char(&self) -> Option<char> {
+        let mut chars = self.as_str().chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// The number of columns the grapheme takes up on the terminal.
+    ///
+    /// Terminals don't agree on this for every grapheme. We go with the width of the first
+    /// character, except that emoji sequences and flags are always two columns wide, as they are
+    /// in most modern terminals. A grapheme which starts with a zero-width character, eg. a lone
+    /// combining mark, still gets a column of its own. Control characters, including the `'\0'`
+    /// which fills the cells covered by a wide character, take up no columns.
+    pub fn width(&self) -> usize {
+        let s = self.as_str();
+        let first = match s.chars().next() {
+            Some(first) => first,
+            None => return 0,
+        };
+        if first.is_control() {
+            return 0;
+        }
+        let width = cmp::max(first.width().unwrap_or(0), 1);
+        let regional_indicator = ('\u{1f1e6}'..='\u{1f1ff}').contains(&first);
+        // U+FE0F asks for the preceding character to be shown as an emoji.
+        if regional_indicator || s.contains('\u{fe0f}') {
+            cmp::max(width, 2)
+        } else {
+            width
+        }
+    }
+
+    /// The grapheme as it should be written to the terminal. Terminals attach a grapheme which
+    /// starts with a zero-width character to whatever is in the cell before it, so it gets a space
+    /// to sit on instead.
+    pub(crate) fn for_terminal(&self) -> Cow<'_, str> {
+        let s = self.as_str();
+        match s.chars().next() {
+            Some(first) if !first.is_control() && first.width() == Some(0) => {
+                Cow::Owned(format!(" {}", s))
+            },
+            _ => Cow::Borrowed(s),
+        }
+    }
+}
+
+impl From<char> for Grapheme {
+    fn from(c: char) -> Grapheme {
+        Grapheme::from_char(c)
+    }
+}
+
+impl PartialEq<char> for Grapheme {
+    fn eq(&self, c: &char) -> bool {
+        self.as_char() == Some(*c)
+    }
+}
+
+impl fmt::Display for Grapheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Grapheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::graphics::Surface;
+
+    #[test]
+    fn width() {
+        assert_eq!(Grapheme::new("a").width(), 1);
+        assert_eq!(Grapheme::new("e\u{301}").width(), 1);
+        assert_eq!(Grapheme::new("\u{4e16}").width(), 2);
+        assert_eq!(Grapheme::new("\u{1f1ec}\u{1f1e7}").width(), 2);
+        assert_eq!(Grapheme::new("\u{2764}\u{fe0f}").width(), 2);
+        assert_eq!(Grapheme::new("\u{301}").width(), 1);
+        assert_eq!(Grapheme::new("\u{200d}").width(), 1);
+        assert_eq!(Grapheme::new("\0").width(), 0);
+        assert_eq!(Grapheme::new("").width(), 0);
+    }
+
+    #[test]
+    fn lone_combining_mark_gets_its_own_cell() {
+        let mut surface = Surface::blank(3, 1);
+        surface.print("\u{301}x", 0, 0, Default::default());
+        assert_eq!(surface.cell(0, 0).c.as_str(), "\u{301}");
+        assert_eq!(surface.cell(1, 0).c, 'x');
+        assert_eq!(surface.cell(0, 0).c.for_terminal(), " \u{301}");
+        assert_eq!(surface.cell(1, 0).c.for_terminal(), "x");
+    }
+
+    #[test]
+    fn inline_and_interned_storage() {
+        // Each combining mark takes two bytes.
+        let longest_inline = format!("\u{e9}{}", "\u{301}".repeat(10));
+        let shortest_interned = format!("e{}", "\u{301}".repeat(11));
+        assert_eq!(longest_inline.len(), INLINE_CAPACITY);
+        assert_eq!(shortest_interned.len(), INLINE_CAPACITY + 1);
+
+        let inline = Grapheme::new(&longest_inline);
+        assert!(matches!(inline.repr, Repr::Inline { .. }));
+        assert_eq!(inline.as_str(), longest_inline);
+
+        let interned = Grapheme::new(&shortest_interned);
+        assert!(matches!(interned.repr, Repr::Interned(..)));
+        assert_eq!(interned.as_str(), shortest_interned);
+        // Interning the same string again shares the allocation, so graphemes compare equal.
+        let again = Grapheme::new(&shortest_interned);
+        assert_eq!(again, interned);
+        assert_eq!(again.as_str().as_ptr(), interned.as_str().as_ptr());
+        assert_ne!(again, inline);
+    }
+}
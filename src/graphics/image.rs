@@ -0,0 +1,151 @@
+use super::*;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_IMAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An image made up of 8-bit RGBA pixels, for drawing with `SurfaceMut::draw_image`.
+///
+/// Images can't be changed once they've been created, and cloning one shares its pixels rather
+/// than copying them. This is what lets the screen tell that an image it's already showing is
+/// the same one being drawn again, so that it doesn't get sent to the terminal every frame.
+#[derive(Clone)]
+pub struct RgbaImage {
+    width: u32,
+    height: u32,
+    pixels: Arc<[u8]>,
+    id: u64,
+}
+
+impl RgbaImage {
+    /// Create an image from its pixels, given row by row with four bytes (red, green, blue,
+    /// alpha) per pixel.
+    ///
+    /// # Panics
+    ///
+    /// If `pixels` isn't `width * height * 4` bytes long.
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> RgbaImage {
+        assert_eq!(pixels.len(), width as usize * height as usize * 4);
+        RgbaImage {
+            width,
+            height,
+            pixels: pixels.into(),
+            id: NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Get the image's width, in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Get the image's height, in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Get the image's pixel data.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Get the pixel at (x, y) as `[r, g, b, a]`.
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+}
+
+impl fmt::Debug for RgbaImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RgbaImage")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+/// The ways of showing images on a terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageProtocol {
+    /// The kitty graphics protocol. Also supported by WezTerm, Ghostty and Konsole.
+    Kitty,
+    /// Sixel graphics, supported by foot, mlterm, Contour, WezTerm and xterm (when emulating a
+    /// VT340), among others.
+    Sixel,
+    /// iTerm2's inline images. Also supported by WezTerm and mintty.
+    Iterm2,
+    /// Draw images out of half-block characters, two pixels to a cell. This works on any
+    /// terminal, but looks blocky.
+    HalfBlocks,
+}
+
+/// An image drawn on a surface.
+#[derive(Clone)]
+pub(crate) struct ImagePlacement {
+    pub image: RgbaImage,
+    /// The cells the whole image is scaled to cover, in the surface's coordinates. This can
+    /// stick out past the edges of the surface.
+    pub rect: Rect,
+    /// The part of `rect` which is actually shown.
+    pub visible: Rect,
+}
+
+impl ImagePlacement {
+    /// Whether this is the same image, drawn in the same place.
+    pub fn same_as(&self, other: &ImagePlacement) -> bool {
+        self.image.id == other.image.id && self.rect == other.rect && self.visible == other.visible
+    }
+
+    /// Whether the image covers the cell at (x, y).
+    pub fn covers(&self, x: u16, y: u16) -> bool {
+        self.visible.contains(x as i16, y as i16)
+    }
+
+    /// Get the part of the image which is visible, and the size in pixels to scale it to so that
+    /// it covers `visible` at `cell_w` by `cell_h` pixels per cell.
+    pub fn visible_pixels(&self, cell_w: u32, cell_h: u32) -> (RgbaImage, u32, u32) {
+        let (x0, y0, x1, y1) = self.source_rect();
+        let w = cmp::max(x1 - x0, 1);
+        let h = cmp::max(y1 - y0, 1);
+        let mut pixels = Vec::with_capacity(w as usize * h as usize * 4);
+        for y in y0..(y0 + h) {
+            for x in x0..(x0 + w) {
+                let x = cmp::min(x, self.image.width.saturating_sub(1));
+                let y = cmp::min(y, self.image.height.saturating_sub(1));
+                pixels.extend_from_slice(&self.image.pixel(x, y));
+            }
+        }
+        let cropped = RgbaImage::new(w, h, pixels);
+        let out_w = self.visible.width() as u32 * cell_w;
+        let out_h = self.visible.height() as u32 * cell_h;
+        (cropped, out_w, out_h)
+    }
+
+    /// The pixels of the image which land in `visible`, as `(x0, y0, x1, y1)`.
+    pub fn source_rect(&self) -> (u32, u32, u32, u32) {
+        let scale = |offset: i16, cells: u16, pixels: u32| {
+            (offset as u64 * pixels as u64 / cmp::max(cells, 1) as u64) as u32
+        };
+        let (w, h) = (self.rect.width(), self.rect.height());
+        let x0 = scale(self.visible.x0 - self.rect.x0, w, self.image.width);
+        let x1 = scale(self.visible.x1 - self.rect.x0, w, self.image.width);
+        let y0 = scale(self.visible.y0 - self.rect.y0, h, self.image.height);
+        let y1 = scale(self.visible.y1 - self.rect.y0, h, self.image.height);
+        (x0, y0, x1, y1)
+    }
+}
+
+/// Scale an image to `w` by `h` pixels, picking the nearest pixel.
+pub(crate) fn scale_nearest(image: &RgbaImage, w: u32, h: u32) -> Vec<[u8; 4]> {
+    let mut out = Vec::with_capacity(w as usize * h as usize);
+    for y in 0..h {
+        let src_y = (y as u64 * image.height as u64 / cmp::max(h, 1) as u64) as u32;
+        for x in 0..w {
+            let src_x = (x as u64 * image.width as u64 / cmp::max(w, 1) as u64) as u32;
+            out.push(image.pixel(src_x, src_y));
+        }
+    }
+    out
+}
@@ -0,0 +1,44 @@
+use super::*;
+
+use std::collections::HashSet;
+use std::fmt;
+
+lazy_static! {
+    static ref INTERNED: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// A hyperlink, for making text clickable in terminals which support it (via OSC 8).
+///
+/// URLs are interned so that links are cheap to copy and compare, which lets them be part of a
+/// `Style`. Interned URLs are never freed, so avoid generating an endless stream of unique ones.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Link {
+    url: &'static str,
+}
+
+impl Link {
+    /// Get the link for a URL.
+    pub fn new(url: &str) -> Link {
+        let mut interned = INTERNED.lock().unwrap();
+        let url = match interned.get(url) {
+            Some(url) => *url,
+            None => {
+                let url: &'static str = Box::leak(url.to_owned().into_boxed_str());
+                interned.insert(url);
+                url
+            },
+        };
+        Link { url }
+    }
+
+    /// The URL that the link points to.
+    pub fn url(&self) -> &'static str {
+        self.url
+    }
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Link").field(&self.url).finish()
+    }
+}
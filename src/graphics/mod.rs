@@ -2,11 +2,21 @@
 use super::*;
 
 mod color;
+mod cursor;
+mod export;
+mod grapheme;
+mod image;
+mod link;
 mod style;
 mod surface;
 mod rect;
 
 pub use color::*;
+pub use cursor::*;
+pub use grapheme::*;
+pub use image::{ImageProtocol, RgbaImage};
+pub(crate) use image::{scale_nearest, ImagePlacement};
+pub use link::*;
 pub use style::*;
 pub use surface::*;
 pub use rect::*;
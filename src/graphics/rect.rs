@@ -1,7 +1,7 @@
 use super::*;
 
 /// A rectangle representing a region of a surface.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub x0: i16,
     pub x1: i16,
@@ -37,5 +37,15 @@ impl Rect {
         self.y1 -= amount;
         self.y1 = cmp::max(self.y0, self.y1);
     }
+
+    /// Whether the point (x, y) lies inside the rectangle.
+    pub fn contains(&self, x: i16, y: i16) -> bool {
+        self.x0 <= x && x < self.x1 && self.y0 <= y && y < self.y1
+    }
+
+    /// Whether the two rectangles overlap.
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x0 < other.x1 && other.x0 < self.x1 && self.y0 < other.y1 && other.y0 < self.y1
+    }
 }
 
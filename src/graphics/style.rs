@@ -1,11 +1,13 @@
 use super::*;
 
 /// The color and styling attributes of a terminal cell.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 pub struct Style {
     pub fg: Color,
     pub bg: Color,
     pub attrs: Attrs,
+    /// Makes the text a hyperlink.
+    pub link: Option<Link>,
 }
 
 impl Style {
@@ -71,10 +73,17 @@ impl Style {
             .. Style::default()
         }
     }
+
+    pub fn link(link: Link) -> Style {
+        Style {
+            link: Some(link),
+            .. Style::default()
+        }
+    }
 }
 
 /// Styling attributes - bold, underlined, etc.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 pub struct Attrs {
     pub bold: bool,
     pub faint: bool,
@@ -137,7 +146,7 @@ impl Attrs {
 }
 
 /// The style of an underline.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct UnderlineStyle {
     pub kind: UnderlineKind,
     pub color: Color,
@@ -167,7 +176,7 @@ impl UnderlineStyle {
 }
 
 /// The kind of underline.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum UnderlineKind {
     Single,
     Double,
@@ -1,10 +1,21 @@
 use super::*;
 
+use unicode_segmentation::UnicodeSegmentation;
+
 /// A single grid cell of text on the terminal.
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Cell {
     pub style: Style,
-    pub c: char,
+    /// The grapheme shown in the cell. The cells covered by the rest of a wide grapheme hold
+    /// `'\0'`.
+    pub c: Grapheme,
+}
+
+impl Cell {
+    /// A cell with nothing in it.
+    pub fn blank() -> Cell {
+        Cell { style: Style::default(), c: Grapheme::from(' ') }
+    }
 }
 
 /// A 2-dimensional arrays of cells.
@@ -12,6 +23,10 @@ pub struct Surface {
     w: u16,
     h: u16,
     cells: Vec<Cell>,
+    cursor_opt: Option<Cursor>,
+    images: Vec<ImagePlacement>,
+    /// Areas which need redrawing even if they haven't changed.
+    invalid: Vec<Rect>,
 }
 
 impl Surface {
@@ -19,12 +34,15 @@ impl Surface {
     pub fn blank(w: u16, h: u16) -> Surface {
         let mut cells = Vec::with_capacity(w as usize * h as usize);
         for _ in 0..(w as usize * h as usize) {
-            cells.push(Cell { style: Style::default(), c: ' ' });
+            cells.push(Cell::blank());
         }
         Surface {
             w: w,
             h: h,
             cells: cells,
+            cursor_opt: None,
+            images: Vec::new(),
+            invalid: Vec::new(),
         }
     }
 
@@ -40,20 +58,89 @@ impl Surface {
         &mut self.cells[i]
     }
 
+    /// Where the cursor should be shown, if anywhere.
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.cursor_opt
+    }
+
+    /// The images drawn on the surface, in the order they were drawn.
+    pub(crate) fn images(&self) -> &[ImagePlacement] {
+        &self.images
+    }
+
+    /// Take the images drawn on the surface, leaving it with none.
+    pub(crate) fn take_images(&mut self) -> Vec<ImagePlacement> {
+        mem::take(&mut self.images)
+    }
+
+    pub(crate) fn set_images(&mut self, images: Vec<ImagePlacement>) {
+        self.images = images;
+    }
+
+    /// Take the areas which have been invalidated with `SurfaceMut::invalidate`.
+    pub(crate) fn take_invalid(&mut self) -> Vec<Rect> {
+        mem::take(&mut self.invalid)
+    }
+
+    /// Create a copy of the surface with a different size. Cells in the region where the old and
+    /// new sizes overlap are kept, the rest are blank.
+    pub(crate) fn resized(&self, w: u16, h: u16) -> Surface {
+        let mut surface = Surface::blank(w, h);
+        let copy_w = cmp::min(w, self.w) as usize;
+        for y in 0..cmp::min(h, self.h) {
+            let start = y as usize * w as usize;
+            surface.cells[start..(start + copy_w)].copy_from_slice(&self.row(y)[..copy_w]);
+        }
+        surface
+    }
+
+    /// Get the cells of row `y`.
+    pub(crate) fn row(&self, y: u16) -> &[Cell] {
+        let w = self.w as usize;
+        let start = y as usize * w;
+        &self.cells[start..(start + w)]
+    }
+
+    /// Shift the rows in the range `y0..y1` up by `amount` rows, or down if `amount` is negative.
+    /// Rows shifted in from outside the range are blank.
+    pub(crate) fn scroll(&mut self, y0: u16, y1: u16, amount: i16) {
+        let w = self.w as usize;
+        let y0 = y0 as usize;
+        let y1 = y1 as usize;
+        let n = cmp::min(amount.unsigned_abs() as usize, y1 - y0);
+        let blank = Cell::blank();
+        if amount > 0 {
+            self.cells.copy_within(((y0 + n) * w)..(y1 * w), y0 * w);
+            for cell in &mut self.cells[((y1 - n) * w)..(y1 * w)] {
+                *cell = blank;
+            }
+        } else {
+            self.cells.copy_within((y0 * w)..((y1 - n) * w), (y0 + n) * w);
+            for cell in &mut self.cells[(y0 * w)..((y0 + n) * w)] {
+                *cell = blank;
+            }
+        }
+    }
+
     /// Set the cell at position (x, y)
     pub fn put(&mut self, c: char, x: i16, y: i16, style: Style) {
+        self.put_grapheme(Grapheme::from(c), x, y, style)
+    }
+
+    /// Set the cell at position (x, y) to a grapheme cluster.
+    pub fn put_grapheme(&mut self, grapheme: Grapheme, x: i16, y: i16, style: Style) {
         let i = match self.index(x, y) {
             Some(i) => i,
             None => return,
         };
-        self.cells[i].c = c;
+        self.cells[i].c = grapheme;
         self.cells[i].style = style;
-        for n in 1..(c.width().unwrap_or(0) as i16) {
+        for n in 1..(grapheme.width() as i16) {
             let i = match self.index(x + n, y) {
                 Some(i) => i,
                 None => return,
             };
-            self.cells[i].c = '\0';
+            self.cells[i].c = Grapheme::from('\0');
             self.cells[i].style = style;
         }
     }
@@ -65,14 +152,14 @@ impl Surface {
     }
 
     fn print_inner(&mut self, text: &str, x0: i16, x1: i16, y: i16, style: Style) {
-        let mut chars = text.chars();
         let mut x = x0;
-        while let Some(c) = chars.next() {
-            let width = c.width().unwrap_or(0) as i16;
+        for grapheme in text.graphemes(true) {
+            let grapheme = Grapheme::new(grapheme);
+            let width = grapheme.width() as i16;
             if x + width > x1 {
                 break;
             }
-            self.put(c, x, y, style);
+            self.put_grapheme(grapheme, x, y, style);
             x += width;
         }
     }
@@ -122,22 +209,18 @@ impl Surface {
                 Some(i) => i,
                 None => break,
             };
-            let c = self.cells[i].c;
-            let v = char_to_segments(c).unwrap_or(0);
+            let v = self.cells[i].c.as_char().and_then(char_to_segments).unwrap_or(0);
             let v = v | 0b0001;
-            let c = segments_to_char(v);
-            self.cells[i].c = c;
+            self.cells[i].c = Grapheme::from(segments_to_char(v));
         }
         for x in (x0 + 1)..(x1 + 1) {
             let i = match self.index(x, y) {
                 Some(i) => i,
                 None => break,
             };
-            let c = self.cells[i].c;
-            let v = char_to_segments(c).unwrap_or(0);
+            let v = self.cells[i].c.as_char().and_then(char_to_segments).unwrap_or(0);
             let v = v | 0b0100;
-            let c = segments_to_char(v);
-            self.cells[i].c = c;
+            self.cells[i].c = Grapheme::from(segments_to_char(v));
         }
     }
 
@@ -148,22 +231,18 @@ impl Surface {
                 Some(i) => i,
                 None => break,
             };
-            let c = self.cells[i].c;
-            let v = char_to_segments(c).unwrap_or(0);
+            let v = self.cells[i].c.as_char().and_then(char_to_segments).unwrap_or(0);
             let v = v | 0b1000;
-            let c = segments_to_char(v);
-            self.cells[i].c = c;
+            self.cells[i].c = Grapheme::from(segments_to_char(v));
         }
         for y in (y0 + 1)..(y1 + 1) {
             let i = match self.index(x, y) {
                 Some(i) => i,
                 None => break,
             };
-            let c = self.cells[i].c;
-            let v = char_to_segments(c).unwrap_or(0);
+            let v = self.cells[i].c.as_char().and_then(char_to_segments).unwrap_or(0);
             let v = v | 0b0010;
-            let c = segments_to_char(v);
-            self.cells[i].c = c;
+            self.cells[i].c = Grapheme::from(segments_to_char(v));
         }
     }
 
@@ -182,12 +261,19 @@ impl Surface {
         let x0 = cmp::max(0, rect.x0) as usize;
         let x1 = cmp::min(self.w as i16, rect.x1) as usize;
 
+        if let Some(cursor) = self.cursor_opt {
+            let (x, y) = (cursor.x as usize, cursor.y as usize);
+            if x0 <= x && x < x1 && y0 <= y && y < y1 {
+                self.cursor_opt = None;
+            }
+        }
+        let cleared = Rect { x0: x0 as i16, x1: x1 as i16, y0: y0 as i16, y1: y1 as i16 };
+        self.images.retain(|placement| !placement.visible.intersects(&cleared));
+
         let w = self.w as usize;
         for y in y0..y1 {
             for x in x0..x1 {
-                let cell = &mut self.cells[x + y * w];
-                cell.style = Style::default();
-                cell.c = ' ';
+                self.cells[x + y * w] = Cell::blank();
             }
         }
     }
@@ -205,6 +291,21 @@ impl<'a> SurfaceRef<'a> {
         self.surface.cell((x as i16 + self.rect.x0) as u16, (y as i16 + self.rect.y0) as u16)
     }
 
+    /// Where the cursor should be shown, if it's been placed inside this region.
+    pub fn cursor(&self) -> Option<Cursor> {
+        let cursor = self.surface.cursor_opt?;
+        let x = cursor.x as i16;
+        let y = cursor.y as i16;
+        if x < self.rect.x0 || x >= self.rect.x1 || y < self.rect.y0 || y >= self.rect.y1 {
+            return None;
+        }
+        Some(Cursor {
+            x: (x - self.rect.x0) as u16,
+            y: (y - self.rect.y0) as u16,
+            .. cursor
+        })
+    }
+
     /// Get the surface's width
     pub fn width(&self) -> u16 {
         self.rect.width()
@@ -318,10 +419,78 @@ impl<'a> SurfaceMut<'a> {
 
     /// Print a single character to a position on the surface.
     pub fn put(&mut self, c: char, x: i16, y: i16, style: Style) {
+        self.put_grapheme(Grapheme::from(c), x, y, style)
+    }
+
+    /// Print a single grapheme cluster to a position on the surface.
+    pub fn put_grapheme(&mut self, grapheme: Grapheme, x: i16, y: i16, style: Style) {
+        if x < 0 || x >= self.width() as i16 || y < 0 || y >= self.height() as i16 {
+            return;
+        }
+        self.surface.put_grapheme(grapheme, x + self.rect.x0, y + self.rect.y0, style);
+    }
+
+    /// Draw an image, scaled to cover the cells in `rect`.
+    ///
+    /// The image is shown using whichever protocol the terminal supports (see `ImageProtocol`),
+    /// falling back to half-block characters. The cells under it are cleared. Drawing anything
+    /// else over those cells afterwards has no effect on terminals which can show images. The
+    /// parts of `rect` which lie outside the surface are cropped off.
+    pub fn draw_image(&mut self, rect: Rect, image: &RgbaImage) {
+        if image.width() == 0 || image.height() == 0 || rect.x1 <= rect.x0 || rect.y1 <= rect.y0 {
+            return;
+        }
+        let rect = Rect {
+            x0: rect.x0 + self.rect.x0,
+            x1: rect.x1 + self.rect.x0,
+            y0: rect.y0 + self.rect.y0,
+            y1: rect.y1 + self.rect.y0,
+        };
+        let visible = Rect {
+            x0: cmp::max(rect.x0, self.rect.x0),
+            x1: cmp::min(rect.x1, self.rect.x1),
+            y0: cmp::max(rect.y0, self.rect.y0),
+            y1: cmp::min(rect.y1, self.rect.y1),
+        };
+        if visible.x1 <= visible.x0 || visible.y1 <= visible.y0 {
+            return;
+        }
+        self.surface.clear(visible);
+        self.surface.images.push(ImagePlacement { image: image.clone(), rect, visible });
+    }
+
+    /// Mark part of the surface as needing to be redrawn on the terminal even if it hasn't
+    /// changed since the last frame, eg. because something other than the UI has drawn over it.
+    ///
+    /// See also `termcandy::redraw`, which redraws the whole screen.
+    pub fn invalidate(&mut self, rect: Rect) {
+        let rect = Rect {
+            x0: cmp::max(rect.x0.saturating_add(self.rect.x0), self.rect.x0),
+            x1: cmp::min(rect.x1.saturating_add(self.rect.x0), self.rect.x1),
+            y0: cmp::max(rect.y0.saturating_add(self.rect.y0), self.rect.y0),
+            y1: cmp::min(rect.y1.saturating_add(self.rect.y0), self.rect.y1),
+        };
+        if rect.x0 < rect.x1 && rect.y0 < rect.y1 {
+            self.surface.invalid.push(rect);
+        }
+    }
+
+    /// Show the terminal's cursor at a position on the surface. eg. so that a text input widget
+    /// can show where text will be inserted, and so that input methods know where to put their
+    /// composition window.
+    ///
+    /// Only one cursor can be shown, so this replaces any cursor that was set previously,
+    /// anywhere on the surface. If nothing sets the cursor while drawing a frame then it's hidden.
+    /// Positions outside of the surface are ignored.
+    pub fn set_cursor(&mut self, x: i16, y: i16, style: CursorStyle) {
         if x < 0 || x >= self.width() as i16 || y < 0 || y >= self.height() as i16 {
             return;
         }
-        self.surface.put(c, x + self.rect.x0, y + self.rect.y0, style);
+        self.surface.cursor_opt = Some(Cursor {
+            x: (x + self.rect.x0) as u16,
+            y: (y + self.rect.y0) as u16,
+            style,
+        });
     }
 }
 
@@ -1,47 +1,45 @@
 use super::*;
 
+use futures::channel::oneshot;
 use slab::Slab;
-use crate::terminal::NonBlockingStdin;
 
-lazy_static! {
-    static ref GLOBAL_WATCHER_SET: Mutex<Option<GlobalWatcherSet>> = Mutex::new(None);
-}
+/// The watcher set of an input-handling session. Shared between the task reading events and the
+/// `EventWatcher`s receiving them.
+pub(crate) type SharedWatcherSet = Arc<Mutex<Option<WatcherSet>>>;
 
 task_local! {
+    static WATCHER_SET: SharedWatcherSet;
+
     // TODO: this doesn't need to be a Mutex
     static EVENT_MAP: Mutex<Vec<&'static (dyn Fn(Event) -> Option<Event> + Sync + Send)>>;
 }
 
-pub(crate) async fn with_input_handling<F: Future>(stdin: NonBlockingStdin, future: F)
-    -> io::Result<F::Output>
+pub(crate) async fn with_input_handling<I, F>(stdin: I, future: F) -> io::Result<F::Output>
+where
+    I: AsyncRead + Unpin + Send + 'static,
+    F: Future,
 {
-    EVENT_MAP.scope(Mutex::new(Vec::new()), async {
-        let event_task = EventTask::new(stdin);
-        let mut global_set_opt = GLOBAL_WATCHER_SET.lock().unwrap();
-        *global_set_opt = Some(GlobalWatcherSet {
-            watcher_wakers: Slab::new(),
-            num_polled_this_round: 0,
-            odd_numbered_round: false,
-            current_event: Poll::Pending,
-            event_task_waker_opt: None,
-        });
-        drop(global_set_opt);
-
-        let join_handle = tokio::spawn(event_task);
-        let ret = future.await;
-        let mut global_set_opt = GLOBAL_WATCHER_SET.lock().unwrap();
-        {
-            let global_set = global_set_opt.as_mut().unwrap();
-            if let Some(event_task_waker) = global_set.event_task_waker_opt.as_ref() {
-                event_task_waker.wake_by_ref();
-            }
+    let watcher_set = WatcherSet::new_shared();
+    let event_task = EventTask::new(stdin, watcher_set.clone());
+    let join_handle = tokio::spawn(event_task);
+    let ret = with_watcher_set(watcher_set.clone(), future).await;
+    let mut watcher_set_opt = watcher_set.lock().unwrap();
+    {
+        let watcher_set = watcher_set_opt.as_mut().unwrap();
+        if let Some(event_task_waker) = watcher_set.event_task_waker_opt.as_ref() {
+            event_task_waker.wake_by_ref();
         }
-        *global_set_opt = None;
-        drop(global_set_opt);
+    }
+    *watcher_set_opt = None;
+    drop(watcher_set_opt);
+
+    join_handle.await.unwrap()?;
+    Ok(ret)
+}
 
-        join_handle.await.unwrap()?;
-        Ok(ret)
-    }).await
+/// Run a future with the given watcher set as its source of input events.
+pub(crate) async fn with_watcher_set<F: Future>(watcher_set: SharedWatcherSet, future: F) -> F::Output {
+    WATCHER_SET.scope(watcher_set, EVENT_MAP.scope(Mutex::new(Vec::new()), future)).await
 }
 
 pub(crate) fn with_event_map<M, F, R>(map: M, func: F) -> R
@@ -72,120 +70,188 @@ where
     }
 }
 
-struct GlobalWatcherSet {
+/// Start waiting for a reply from the terminal. This must be called before sending the query, so
+/// that a quick reply isn't missed. Returns `None` if called outside of an input-handling session.
+pub(crate) fn expect_reply(filter: ReplyFilter) -> Option<oneshot::Receiver<Reply>> {
+    let watcher_set = WATCHER_SET.try_with(|watcher_set| watcher_set.clone()).ok()?;
+    let mut watcher_set_opt = watcher_set.lock().unwrap();
+    let set = watcher_set_opt.as_mut()?;
+    Some(set.reply_waiters.add(filter))
+}
+
+/// Stop reading input from the terminal, or start again. Does nothing outside of an
+/// input-handling session.
+pub(crate) fn pause_input(paused: bool) {
+    let watcher_set = match WATCHER_SET.try_with(|watcher_set| watcher_set.clone()) {
+        Ok(watcher_set) => watcher_set,
+        Err(..) => return,
+    };
+    let mut watcher_set_opt = watcher_set.lock().unwrap();
+    if let Some(set) = watcher_set_opt.as_mut() {
+        set.input_paused = paused;
+        if !paused {
+            if let Some(event_task_waker) = set.event_task_waker_opt.as_ref() {
+                event_task_waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+pub(crate) struct WatcherSet {
     watcher_wakers: Slab<Option<Waker>>,
     num_polled_this_round: usize,
     odd_numbered_round: bool,
     current_event: Poll<Option<Event>>,
     event_task_waker_opt: Option<Waker>,
+    reply_waiters: ReplyWaiters,
+    /// Set while another program is using the terminal, so that we don't steal its input.
+    input_paused: bool,
+}
+
+impl WatcherSet {
+    pub fn new_shared() -> SharedWatcherSet {
+        Arc::new(Mutex::new(Some(WatcherSet {
+            watcher_wakers: Slab::new(),
+            num_polled_this_round: 0,
+            odd_numbered_round: false,
+            current_event: Poll::Pending,
+            event_task_waker_opt: None,
+            reply_waiters: ReplyWaiters::new(),
+            input_paused: false,
+        })))
+    }
+
+    /// Whether every watcher has seen the current event, meaning we're ready to dispatch another
+    /// one.
+    pub fn all_polled(&self) -> bool {
+        self.num_polled_this_round >= self.watcher_wakers.len()
+    }
+
+    /// Start a new round, handing the given event to every watcher.
+    pub fn dispatch(&mut self, event: Poll<Option<Event>>) {
+        self.current_event = event;
+        self.num_polled_this_round = 0;
+        self.odd_numbered_round ^= true;
+
+        for (_key, watcher_opt) in self.watcher_wakers.iter() {
+            if let Some(watcher) = watcher_opt.as_ref() {
+                watcher.wake_by_ref();
+            }
+        }
+    }
 }
 
 #[pin_project]
-struct EventTask {
+struct EventTask<I> {
     #[pin]
-    events: Events,
+    events: Events<I>,
+    watcher_set: SharedWatcherSet,
 }
 
 pub struct EventWatcher {
+    watcher_set: SharedWatcherSet,
     odd_numbered_round: bool,
     key: usize,
 }
 
 impl EventWatcher {
     pub fn new() -> Option<EventWatcher> {
-        let mut global_set_opt = GLOBAL_WATCHER_SET.lock().unwrap();
-        let global_set = global_set_opt.as_mut()?;
-        let key = global_set.watcher_wakers.insert(None);
-        global_set.num_polled_this_round += 1;
-        let odd_numbered_round = !global_set.odd_numbered_round;
-        trace!("Creating EventWatcher. Including us, {} of {} watchers have polled", global_set.num_polled_this_round, global_set.watcher_wakers.len());
-        Some(EventWatcher { odd_numbered_round, key })
+        let watcher_set = WATCHER_SET.try_with(|watcher_set| watcher_set.clone()).ok()?;
+        let mut watcher_set_opt = watcher_set.lock().unwrap();
+        let set = watcher_set_opt.as_mut()?;
+        let key = set.watcher_wakers.insert(None);
+        set.num_polled_this_round += 1;
+        let odd_numbered_round = !set.odd_numbered_round;
+        trace!("Creating EventWatcher. Including us, {} of {} watchers have polled", set.num_polled_this_round, set.watcher_wakers.len());
+        drop(watcher_set_opt);
+        Some(EventWatcher { watcher_set, odd_numbered_round, key })
     }
 }
 
 impl Drop for EventWatcher {
     fn drop(&mut self) {
-        let mut global_set_opt = GLOBAL_WATCHER_SET.lock().unwrap();
-        if let Some(global_set) = global_set_opt.as_mut() {
-            let _watcher_opt = global_set.watcher_wakers.remove(self.key);
+        let mut watcher_set_opt = self.watcher_set.lock().unwrap();
+        if let Some(set) = watcher_set_opt.as_mut() {
+            let _watcher_opt = set.watcher_wakers.remove(self.key);
 
             trace!("dropping EventWatcher");
-            if self.odd_numbered_round != global_set.odd_numbered_round {
+            if self.odd_numbered_round != set.odd_numbered_round {
                 trace!("we had polled. decrementing num_polled_this_round");
-                global_set.num_polled_this_round -= 1;
-            } else if global_set.num_polled_this_round == global_set.watcher_wakers.len() {
+                set.num_polled_this_round -= 1;
+            } else if set.num_polled_this_round == set.watcher_wakers.len() {
                 trace!("we had not polled, all remaining watchers have now polled, waking event task");
-                global_set.current_event = Poll::Pending;
-                if let Some(event_task_waker) = global_set.event_task_waker_opt.as_ref() {
+                set.current_event = Poll::Pending;
+                if let Some(event_task_waker) = set.event_task_waker_opt.as_ref() {
                     event_task_waker.wake_by_ref();
                 }
             }
-            trace!("now that we're gone, {} of {} remaining watchers have polled", global_set.num_polled_this_round, global_set.watcher_wakers.len());
+            trace!("now that we're gone, {} of {} remaining watchers have polled", set.num_polled_this_round, set.watcher_wakers.len());
         }
     }
 }
 
-impl EventTask {
-    pub fn new(stdin: NonBlockingStdin) -> EventTask {
+impl<I: AsyncRead + Unpin> EventTask<I> {
+    pub fn new(stdin: I, watcher_set: SharedWatcherSet) -> EventTask<I> {
         let events = Events::new(stdin);
-        EventTask { events }
+        EventTask { events, watcher_set }
     }
 }
 
-impl Future for EventTask {
+impl<I: AsyncRead + Unpin> Future for EventTask<I> {
     type Output = io::Result<()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = self.project();
         trace!("polling EventTask");
-        let mut global_set_opt = GLOBAL_WATCHER_SET.lock().unwrap();
-        let global_set = match global_set_opt.as_mut() {
-            Some(global_set) => global_set,
+        let mut watcher_set_opt = this.watcher_set.lock().unwrap();
+        let set = match watcher_set_opt.as_mut() {
+            Some(set) => set,
             None => return Poll::Ready(Ok(())),
         };
-        global_set.event_task_waker_opt = Some(cx.waker().clone());
+        set.event_task_waker_opt = Some(cx.waker().clone());
 
-        if global_set.num_polled_this_round < global_set.watcher_wakers.len() {
+        if !set.all_polled() {
             trace!("not everyone has polled yet. sleeping");
             return Poll::Pending;
         }
 
+        if set.input_paused {
+            trace!("input is paused. sleeping");
+            return Poll::Pending;
+        }
+
         trace!("ready to poll the input again");
-        let err_opt = match this.events.poll_next(cx) {
-            Poll::Ready(Some(Ok(event))) => {
-                global_set.current_event = Poll::Ready(Some(event));
-                None
-            },
-            Poll::Ready(None) => {
-                global_set.current_event = Poll::Ready(None);
-                None
-            },
-            Poll::Ready(Some(Err(e))) => {
-                global_set.current_event = Poll::Ready(None);
-                Some(e)
-            },
-            Poll::Pending => {
-                // TODO: is this correct?
-                // This line wasn't here before, just adding it now while refactoring coz it seems
-                // necessary.
-                global_set.current_event = Poll::Pending;
-
-                trace!("input not ready");
-                return Poll::Pending;
-            },
+        let mut events = this.events;
+        let (event, ended, err_opt) = loop {
+            events.as_mut().set_expecting_replies(set.reply_waiters.any_waiting());
+            match events.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(Input::Event(event)))) => {
+                    break (Poll::Ready(Some(event)), false, None);
+                },
+                Poll::Ready(Some(Ok(Input::Reply(reply)))) => {
+                    // Replies aren't for the watchers. Pass it on and look for an event.
+                    set.reply_waiters.deliver(reply);
+                },
+                Poll::Ready(None) => break (Poll::Ready(None), true, None),
+                Poll::Ready(Some(Err(e))) => break (Poll::Ready(None), true, Some(e)),
+                Poll::Pending => {
+                    // TODO: is this correct?
+                    // This line wasn't here before, just adding it now while refactoring coz it
+                    // seems necessary.
+                    set.current_event = Poll::Pending;
+
+                    trace!("input not ready");
+                    return Poll::Pending;
+                },
+            }
         };
         trace!("new input ready. waking everybody");
+        set.dispatch(event);
 
-        global_set.num_polled_this_round = 0;
-        global_set.odd_numbered_round ^= true;
-
-        for (_key, watcher_opt) in global_set.watcher_wakers.iter() {
-            if let Some(watcher) = watcher_opt.as_ref() {
-                watcher.wake_by_ref();
-            }
-        }
-
+        // Once the input has ended there's nothing more to read. The watchers are left with the
+        // end of the stream.
         match err_opt {
+            None if ended => Poll::Ready(Ok(())),
             None => Poll::Pending,
             Some(err) => Poll::Ready(Err(err)),
         }
@@ -200,23 +266,23 @@ impl Stream for EventWatcher {
     {
         let this = Pin::into_inner(self);
         trace!("polling EventWatcher");
-        let mut global_set_opt = GLOBAL_WATCHER_SET.lock().unwrap();
-        let global_set = match global_set_opt.as_mut() {
-            Some(global_set) => global_set,
+        let mut watcher_set_opt = this.watcher_set.lock().unwrap();
+        let set = match watcher_set_opt.as_mut() {
+            Some(set) => set,
             None => return Poll::Ready(None),
         };
-        global_set.watcher_wakers[this.key] = Some(cx.waker().clone());
+        set.watcher_wakers[this.key] = Some(cx.waker().clone());
 
-        if this.odd_numbered_round != global_set.odd_numbered_round {
+        if this.odd_numbered_round != set.odd_numbered_round {
             trace!("already polled this round");
             return Poll::Pending;
         }
 
         trace!("we haven't polled yet this round (odd = {})", this.odd_numbered_round);
-        global_set.num_polled_this_round += 1;
+        set.num_polled_this_round += 1;
         this.odd_numbered_round ^= true;
 
-        let ret = match global_set.current_event {
+        let ret = match set.current_event {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Ready(Some(ref event)) => {
@@ -238,11 +304,11 @@ impl Stream for EventWatcher {
         };
 
         trace!("got ret == {:?}", ret);
-        trace!("{} of {} watchers have polled", global_set.num_polled_this_round, global_set.watcher_wakers.len());
-        if global_set.num_polled_this_round == global_set.watcher_wakers.len() {
+        trace!("{} of {} watchers have polled", set.num_polled_this_round, set.watcher_wakers.len());
+        if set.num_polled_this_round == set.watcher_wakers.len() {
             trace!("everyone has polled. waking the event task");
-            global_set.current_event = Poll::Pending;
-            if let Some(event_task_waker) = global_set.event_task_waker_opt.as_ref() {
+            set.current_event = Poll::Pending;
+            if let Some(event_task_waker) = set.event_task_waker_opt.as_ref() {
                 event_task_waker.wake_by_ref();
             }
         }
@@ -253,7 +319,7 @@ impl Stream for EventWatcher {
 
 impl FusedStream for EventWatcher {
     fn is_terminated(&self) -> bool {
-        GLOBAL_WATCHER_SET.lock().unwrap().is_none()
+        self.watcher_set.lock().unwrap().is_none()
     }
 }
 
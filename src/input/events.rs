@@ -1,6 +1,6 @@
 use super::*;
 
-use crate::terminal::NonBlockingStdin;
+use std::collections::VecDeque;
 use tokio::time::Instant;
 use termion::event::{Event, Key};
 
@@ -8,56 +8,174 @@ use crate::cycle_buffer::CycleBuffer;
 
 const BUFFER_SIZE: usize = 1024;
 
+/// How long to wait for the rest of an escape sequence before deciding that the user pressed
+/// escape, or alt along with some other key.
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The longest OSC or DCS reply we'll collect. Big enough for a clipboard's worth of base64.
+const MAX_STRING_LEN: usize = 4 * 1024 * 1024;
+
+/// Something read from the terminal.
+pub(crate) enum Input {
+    Event(Event),
+    Reply(Reply),
+}
+
 #[pin_project]
-pub struct Events {
+pub(crate) struct Events<I> {
     #[pin]
-    inner: NonBlockingStdin,
+    inner: I,
     cycle_buffer: CycleBuffer<BUFFER_SIZE>,
     #[pin]
     escape_timeout: Option<tokio::time::Sleep>,
+    /// The part of an OSC or DCS reply read so far. These can be much bigger than the buffer (eg.
+    /// the contents of the clipboard), so they're moved out of it as they arrive.
+    string_opt: Option<(StringKind, Vec<u8>)>,
+    /// Whether anyone is waiting for a reply. See `set_expecting_replies`.
+    expecting_replies: bool,
+    /// Events parsed out of input which looked like the start of a reply but turned out not to be.
+    replayed: VecDeque<Event>,
+    /// Set once the input has reached end-of-file, eg. because the other end of a socket hung up.
+    eof: bool,
 }
 
-impl Events {
-    pub fn new(stdin: NonBlockingStdin) -> Events {
+impl<I: AsyncRead + Unpin> Events<I> {
+    pub fn new(stdin: I) -> Events<I> {
         Events {
             inner: stdin,
             cycle_buffer: CycleBuffer::new(),
             escape_timeout: None,
+            string_opt: None,
+            expecting_replies: false,
+            replayed: VecDeque::new(),
+            eof: false,
         }
     }
+
+    /// Say whether any replies are expected. OSC and DCS replies are only picked out of the input
+    /// while they are. Otherwise `ESC ]` and `ESC P` are alt-] and alt-P, and whatever follows is
+    /// more keys, which mustn't be swallowed while we wait for a terminator that isn't coming.
+    pub fn set_expecting_replies(self: Pin<&mut Self>, expecting_replies: bool) {
+        *self.project().expecting_replies = expecting_replies;
+    }
 }
 
-impl Stream for Events {
-    type Item = io::Result<Event>;
+impl<I: AsyncRead + Unpin> Stream for Events<I> {
+    type Item = io::Result<Input>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>)
-        -> Poll<Option<io::Result<Event>>> {
+        -> Poll<Option<io::Result<Input>>> {
         trace!("in Events::poll()");
 
         let mut this = self.project();
         loop {
+            if let Some(event) = this.replayed.pop_front() {
+                return Poll::Ready(Some(Ok(Input::Event(event))));
+            }
+
             // fill our read buffer
-            loop {
-                if let Some(mut cycle_read_buf) = this.cycle_buffer.get_uninitialized() {
-                    let read_buf = cycle_read_buf.as_mut();
+            let mut input_pending = false;
+            while !*this.eof {
+                let mut cycle_read_buf = match this.cycle_buffer.get_uninitialized() {
+                    Some(cycle_read_buf) => cycle_read_buf,
+                    None => break,
+                };
+                let read_buf = cycle_read_buf.as_mut();
+
+                match this.inner.as_mut().poll_read(cx, read_buf) {
+                    Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                    Poll::Ready(Ok(())) => {
+                        if read_buf.filled().is_empty() {
+                            *this.eof = true;
+                        }
+                    },
+                    Poll::Pending => {
+                        input_pending = true;
+                        break;
+                    },
+                }
+            }
 
-                    match this.inner.as_mut().poll_read(cx, read_buf) {
-                        Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
-                        Poll::Ready(Ok(())) => (),
-                        Poll::Pending => break,
+            if let Some((_, string)) = this.string_opt.as_mut() {
+                let len_before = string.len();
+                if read_string(this.cycle_buffer, string) {
+                    this.escape_timeout.set(None);
+                    let reply = match this.string_opt.take().unwrap() {
+                        (StringKind::Osc, string) => Reply::Osc(string),
+                        (StringKind::Dcs, string) => Reply::Dcs(string),
+                    };
+                    return Poll::Ready(Some(Ok(Input::Reply(reply))));
+                }
+                // Give up on the reply if it stalls, or grows too long, or the input ends. It's
+                // most likely someone typing alt-] or alt-P while a reply is expected.
+                let abandon = string.len() > MAX_STRING_LEN || *this.eof || {
+                    if string.len() > len_before {
+                        let deadline = Instant::now() + ESCAPE_TIMEOUT;
+                        this.escape_timeout.set(Some(tokio::time::sleep_until(deadline)));
                     }
+                    match this.escape_timeout.as_mut().as_pin_mut() {
+                        Some(escape_timeout) => escape_timeout.poll(cx).is_ready(),
+                        None => true,
+                    }
+                };
+                if abandon {
+                    this.escape_timeout.set(None);
+                    let (kind, string) = this.string_opt.take().unwrap();
+                    let mut bytes = kind.introducer().to_vec();
+                    bytes.extend_from_slice(&string);
+                    this.replayed.extend(parse_events(&bytes));
+                    continue;
+                }
+                if input_pending {
+                    return Poll::Pending;
                 }
+                continue;
+            }
+
+            match reply_start(this.cycle_buffer, *this.expecting_replies) {
+                ReplyStart::String(kind) => {
+                    let deadline = Instant::now() + ESCAPE_TIMEOUT;
+                    this.escape_timeout.set(Some(tokio::time::sleep_until(deadline)));
+                    this.cycle_buffer.consume_initialized(2);
+                    *this.string_opt = Some((kind, Vec::new()));
+                    continue;
+                },
+                ReplyStart::Csi(len) => {
+                    this.escape_timeout.set(None);
+                    let mut iter = this.cycle_buffer.iter_initialized();
+                    let csi: Vec<u8> = (&mut iter).take(len).skip(2).collect();
+                    iter.consume_read();
+                    return Poll::Ready(Some(Ok(Input::Reply(Reply::Csi(csi)))));
+                },
+                ReplyStart::Maybe if *this.eof => (),
+                ReplyStart::Maybe => {
+                    // Either this is the start of a reply, or it's alt-] or alt-P. Give the rest
+                    // of the reply the same time to turn up that an escape sequence gets.
+                    match this.escape_timeout.as_mut().as_pin_mut() {
+                        None => {
+                            let deadline = Instant::now() + ESCAPE_TIMEOUT;
+                            this.escape_timeout.set(Some(tokio::time::sleep_until(deadline)));
+                            continue;
+                        },
+                        Some(escape_timeout) => match escape_timeout.poll(cx) {
+                            Poll::Ready(()) => this.escape_timeout.set(None),
+                            Poll::Pending => return Poll::Pending,
+                        },
+                    }
+                },
+                ReplyStart::No => (),
             }
 
             let mut iter = this.cycle_buffer.iter_initialized();
             let c = match iter.next() {
                 Some(c) => c,
+                None if *this.eof => return Poll::Ready(None),
                 None => return Poll::Pending,
             };
             match termion::event::parse_event(c, &mut (&mut iter).map(Ok)) {
                 Ok(event) => {
                     iter.consume_read();
-                    return Poll::Ready(Some(Ok(event)));
+                    return Poll::Ready(Some(Ok(Input::Event(event))));
                 },
                 Err(_) => {
                     // Termion failed to parse an event from the input. Either the input buffer
@@ -100,12 +218,20 @@ impl Stream for Events {
                             if timer_running {
                                 this.escape_timeout.set(None);
                                 this.cycle_buffer.consume_initialized(1);
-                                return Poll::Ready(Some(Ok(Event::Key(Key::Esc))));
+                                let event = Event::Key(Key::Esc);
+                                return Poll::Ready(Some(Ok(Input::Event(event))));
                             } else {
-                                let deadline = Instant::now() + Duration::from_millis(200);
+                                let deadline = Instant::now() + ESCAPE_TIMEOUT;
                                 this.escape_timeout.set(Some(tokio::time::sleep_until(deadline)));
                                 continue;
                             }
+                        } else if *this.eof {
+                            // The input ended part way through an event. There's no more coming,
+                            // so pass on what there is.
+
+                            let bytes = iter.take_read();
+                            let event = Event::Unsupported(bytes);
+                            return Poll::Ready(Some(Ok(Input::Event(event))));
                         } else {
                             // The buffer contains something else unparseable. We probably need to
                             // wait for more input to arrive.
@@ -119,7 +245,7 @@ impl Stream for Events {
 
                         let bytes = iter.take_read();
                         let event = Event::Unsupported(bytes);
-                        return Poll::Ready(Some(Ok(event)));
+                        return Poll::Ready(Some(Ok(Input::Event(event))));
                     }
                 },
             }
@@ -127,3 +253,249 @@ impl Stream for Events {
     }
 }
 
+
+/// The replies which are strings, running until a terminator rather than a final byte.
+enum StringKind {
+    Osc,
+    Dcs,
+}
+
+impl StringKind {
+    fn introducer(&self) -> &'static [u8] {
+        match self {
+            StringKind::Osc => b"\x1b]",
+            StringKind::Dcs => b"\x1bP",
+        }
+    }
+}
+
+enum ReplyStart {
+    /// The buffer starts with an OSC or DCS reply.
+    String(StringKind),
+    /// The buffer starts with a complete CSI reply, this many bytes long.
+    Csi(usize),
+    /// The buffer holds the start of what may or may not be a reply.
+    Maybe,
+    No,
+}
+
+/// Check whether the buffer starts with a reply. OSC and DCS replies are only looked for if
+/// `expecting_strings` is set.
+///
+/// OSC replies always start with the number of the command they're answering, and DCS replies
+/// with a digit or `>`. That's what tells them apart from alt-] or alt-P followed by more input,
+/// unless the user happens to type a digit next. The CSI replies we ask for all start with `?`
+/// or `>`, which no key sends.
+fn reply_start(
+    cycle_buffer: &mut CycleBuffer<BUFFER_SIZE>,
+    expecting_strings: bool,
+) -> ReplyStart {
+    let mut iter = cycle_buffer.iter_initialized();
+    match (iter.next(), iter.next(), iter.next()) {
+        (Some(0x1b), Some(b']'), Some(b'0'..=b'9')) if expecting_strings => {
+            ReplyStart::String(StringKind::Osc)
+        },
+        (Some(0x1b), Some(b'P'), Some(b'0'..=b'9')) |
+        (Some(0x1b), Some(b'P'), Some(b'>')) if expecting_strings => {
+            ReplyStart::String(StringKind::Dcs)
+        },
+        (Some(0x1b), Some(b']'), None) |
+        (Some(0x1b), Some(b'P'), None) if expecting_strings => ReplyStart::Maybe,
+        (Some(0x1b), Some(b'['), Some(b'?')) | (Some(0x1b), Some(b'['), Some(b'>')) => {
+            let mut len = 3;
+            loop {
+                match iter.next() {
+                    // Parameters and intermediate bytes.
+                    Some(0x20..=0x3f) => len += 1,
+                    Some(0x40..=0x7e) => return ReplyStart::Csi(len + 1),
+                    Some(..) => return ReplyStart::No,
+                    None => return ReplyStart::Maybe,
+                }
+            }
+        },
+        _ => ReplyStart::No,
+    }
+}
+
+/// Move the rest of an OSC or DCS reply out of the buffer and into `string`, up to the BEL or ST
+/// which ends it. Returns whether the end was found, in which case the terminator is dropped.
+fn read_string(cycle_buffer: &mut CycleBuffer<BUFFER_SIZE>, string: &mut Vec<u8>) -> bool {
+    loop {
+        let mut consumed = 0;
+        let mut terminated = false;
+        for &byte in cycle_buffer.get_initialized() {
+            consumed += 1;
+            if byte == 0x07 {
+                terminated = true;
+                break;
+            }
+            if byte == b'\\' && string.last() == Some(&0x1b) {
+                string.pop();
+                terminated = true;
+                break;
+            }
+            string.push(byte);
+        }
+        if consumed == 0 {
+            return false;
+        }
+        cycle_buffer.consume_initialized(consumed);
+        if terminated {
+            return true;
+        }
+    }
+}
+
+/// Parse bytes which turned out not to be a reply into the events they'd be as ordinary input.
+fn parse_events(bytes: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut rest = bytes;
+    while let Some((&c, tail)) = rest.split_first() {
+        if c == 0x1b && tail.first() == Some(&b'[') && !csi_complete(&tail[1..]) {
+            // Termion panics on a control sequence which has been cut off.
+            events.push(Event::Unsupported(rest.to_vec()));
+            break;
+        }
+        let mut iter = tail.iter();
+        let res = termion::event::parse_event(c, &mut (&mut iter).map(|&b| Ok(b)));
+        let next = iter.as_slice();
+        let read = &rest[..(rest.len() - next.len())];
+        events.push(res.unwrap_or_else(|_| Event::Unsupported(read.to_vec())));
+        rest = next;
+    }
+    events
+}
+
+/// Whether `bytes`, which follow an `ESC [`, hold the rest of a control sequence.
+fn csi_complete(bytes: &[u8]) -> bool {
+    match bytes.first() {
+        // An X10 mouse report, with three bytes of button and position.
+        Some(b'M') => bytes.len() >= 4,
+        _ => bytes.iter().any(|&b| (0x40..=0x7e).contains(&b)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    fn events(expecting_replies: bool) -> (Pin<Box<Events<DuplexStream>>>, DuplexStream) {
+        let (reader, writer) = tokio::io::duplex(BUFFER_SIZE);
+        let mut events = Box::pin(Events::new(reader));
+        events.as_mut().set_expecting_replies(expecting_replies);
+        (events, writer)
+    }
+
+    async fn next(events: &mut Pin<Box<Events<DuplexStream>>>) -> Option<Input> {
+        let next = tokio::time::timeout(Duration::from_secs(5), events.next()).await;
+        next.expect("timed out waiting for input").map(Result::unwrap)
+    }
+
+    async fn next_event(events: &mut Pin<Box<Events<DuplexStream>>>) -> Event {
+        match next(events).await {
+            Some(Input::Event(event)) => event,
+            Some(Input::Reply(reply)) => panic!("expected an event, got {:?}", reply),
+            None => panic!("expected an event, got the end of the input"),
+        }
+    }
+
+    async fn next_reply(events: &mut Pin<Box<Events<DuplexStream>>>) -> Reply {
+        match next(events).await {
+            Some(Input::Reply(reply)) => reply,
+            Some(Input::Event(event)) => panic!("expected a reply, got {:?}", event),
+            None => panic!("expected a reply, got the end of the input"),
+        }
+    }
+
+    #[tokio::test]
+    async fn osc_reply_while_expected() {
+        let (mut events, mut writer) = events(true);
+        writer.write_all(b"\x1b]11;rgb:0/0/0\x07a\x1b]52;c;aGk=\x1b\\").await.unwrap();
+        match next_reply(&mut events).await {
+            Reply::Osc(osc) => assert_eq!(osc, b"11;rgb:0/0/0"),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('a')));
+        match next_reply(&mut events).await {
+            Reply::Osc(osc) => assert_eq!(osc, b"52;c;aGk="),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[tokio::test]
+    async fn alt_bracket_then_digit_without_waiter() {
+        let (mut events, mut writer) = events(false);
+        writer.write_all(b"\x1b]1x").await.unwrap();
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Alt(']')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('1')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('x')));
+    }
+
+    #[tokio::test]
+    async fn stalled_osc_is_passed_on_as_keys() {
+        let (mut events, mut writer) = events(true);
+        writer.write_all(b"\x1b]1x").await.unwrap();
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Alt(']')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('1')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('x')));
+        // Input carries on as normal afterwards.
+        writer.write_all(b"y").await.unwrap();
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('y')));
+    }
+
+    #[tokio::test]
+    async fn osc_cut_off_by_end_of_input() {
+        let (mut events, mut writer) = events(true);
+        writer.write_all(b"\x1b]1x").await.unwrap();
+        drop(writer);
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Alt(']')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('1')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('x')));
+        assert!(next(&mut events).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dcs_reply_while_expected() {
+        let (mut events, mut writer) = events(true);
+        writer.write_all(b"\x1bP>|kitty(0.31.0)\x1b\\\x1bP1+r5463\x1b\\").await.unwrap();
+        match next_reply(&mut events).await {
+            Reply::Dcs(dcs) => assert_eq!(dcs, b">|kitty(0.31.0)"),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        match next_reply(&mut events).await {
+            Reply::Dcs(dcs) => assert_eq!(dcs, b"1+r5463"),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[tokio::test]
+    async fn alt_p_then_digit_without_waiter() {
+        let (mut events, mut writer) = events(false);
+        writer.write_all(b"\x1bP>\x1bP2").await.unwrap();
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Alt('P')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('>')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Alt('P')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('2')));
+    }
+
+    #[tokio::test]
+    async fn stalled_dcs_is_passed_on_as_keys() {
+        let (mut events, mut writer) = events(true);
+        writer.write_all(b"\x1bP>q").await.unwrap();
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Alt('P')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('>')));
+        assert_eq!(next_event(&mut events).await, Event::Key(Key::Char('q')));
+    }
+
+    #[test]
+    fn parse_events_passes_on_garbage() {
+        let events = parse_events(b"a\x1b[A\x1b[99");
+        assert_eq!(events, vec![
+            Event::Key(Key::Char('a')),
+            Event::Key(Key::Up),
+            Event::Unsupported(b"\x1b[99".to_vec()),
+        ]);
+    }
+}
@@ -3,9 +3,11 @@ use super::*;
 mod events;
 mod event_watcher;
 mod event_stream;
+mod reply;
 
 use self::events::*;
 pub(crate) use self::event_watcher::*;
 pub use self::event_stream::*;
+pub(crate) use self::reply::*;
 
 
@@ -0,0 +1,72 @@
+//! Replies to queries we've sent the terminal.
+//!
+//! Some things can only be found out by asking the terminal, which answers by sending an escape
+//! sequence back down the input stream. `Events` picks these out of the input, and rather than
+//! being handed to widgets they go to whoever is waiting for them.
+
+use super::*;
+
+use futures::channel::oneshot;
+
+/// A reply from the terminal.
+#[derive(Debug)]
+pub(crate) enum Reply {
+    /// An operating system command: everything between `ESC ]` and the terminator.
+    Osc(Vec<u8>),
+    /// A control sequence: everything after `ESC [`, up to and including the final byte.
+    Csi(Vec<u8>),
+    /// A device control string: everything between `ESC P` and the terminator.
+    Dcs(Vec<u8>),
+}
+
+/// Decides whether a reply is the one a waiter is after.
+pub(crate) type ReplyFilter = Box<dyn Fn(&Reply) -> bool + Send>;
+
+/// The queries waiting on a reply. Terminals answer queries in the order they're sent, so replies
+/// go to the longest-waiting waiter that wants them.
+pub(crate) struct ReplyWaiters {
+    waiters: Vec<(ReplyFilter, oneshot::Sender<Reply>)>,
+}
+
+impl ReplyWaiters {
+    pub fn new() -> ReplyWaiters {
+        ReplyWaiters { waiters: Vec::new() }
+    }
+
+    pub fn add(&mut self, filter: ReplyFilter) -> oneshot::Receiver<Reply> {
+        self.waiters.retain(|(_, sender)| !sender.is_canceled());
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.push((filter, sender));
+        receiver
+    }
+
+    /// Whether anyone is still waiting for a reply.
+    pub fn any_waiting(&mut self) -> bool {
+        self.waiters.retain(|(_, sender)| !sender.is_canceled());
+        !self.waiters.is_empty()
+    }
+
+    /// Hand a reply to whoever's waiting for it. Replies nobody wants, eg. ones which arrive after
+    /// the waiter gave up, are dropped.
+    pub fn deliver(&mut self, reply: Reply) {
+        self.waiters.retain(|(_, sender)| !sender.is_canceled());
+        let index = match self.waiters.iter().position(|(filter, _)| filter(&reply)) {
+            Some(index) => index,
+            None => {
+                trace!("dropping unwanted reply {:?}", reply);
+                return;
+            },
+        };
+        let (_, sender) = self.waiters.remove(index);
+        let _ = sender.send(reply);
+    }
+}
+
+/// Wait for a reply, giving up after `timeout` since many terminals ignore queries they don't
+/// understand.
+pub(crate) async fn wait_for_reply(
+    receiver: oneshot::Receiver<Reply>,
+    timeout: Duration,
+) -> Option<Reply> {
+    tokio::time::timeout(timeout, receiver).await.ok()?.ok()
+}
@@ -7,6 +7,7 @@
 #![feature(result_flattening)]
 #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_slice)]
+#![feature(unsigned_abs)]
 #![allow(incomplete_features)]
 
 use {
@@ -41,9 +42,14 @@ use {
 
 pub use {
     crate::{
-        run::run,
+        run::{run, run_with, run_with_options, RunOptions},
+        driver::Driver,
         widget::{Widget, FutureExt},
-        screen::screen_size,
+        screen::{
+            capabilities, is_dark_background, print_above, redraw, report_working_directory,
+            screen_size, suspend, terminal_icon_name, terminal_title, with_terminal_released,
+            Capabilities,
+        },
     },
     termcandy_macros::{
         widget, select_widget,
@@ -51,12 +57,19 @@ pub use {
 };
 
 mod terminal;
+pub mod backend;
 pub mod graphics;
 mod screen;
 mod run;
+mod driver;
 pub mod input;
+pub mod clipboard;
+pub mod server;
 mod cycle_buffer;
+mod base64;
 pub mod widget;
+#[cfg(feature = "testing")]
+pub mod testing;
 #[doc(hidden)]
 pub mod macros_impl;
 
@@ -1,56 +1,160 @@
 use super::*;
 
-use crate::screen::Screen;
+use std::path::PathBuf;
+
+use crate::backend::{Backend, TtyBackend};
+use crate::graphics::{ColorDepth, ImageProtocol};
+use crate::screen::{RecordInput, Recorder, Screen};
 use crate::widget::Widget;
-use crate::terminal::non_blocking_stdio;
 
+use tokio::time::{self, Instant, Sleep};
+
+/// Run a widget in the terminal, returning its output once it completes.
 pub async fn run<W>(widget: W) -> io::Result<W::Output>
 where
     W: Widget,
 {
-    let (stdin, stdout) = non_blocking_stdio()?;
-    crate::input::with_input_handling(stdin, {
-        crate::screen::with_screen(stdout, |screen| {
+    run_with(TtyBackend::new()?, widget).await
+}
+
+/// Options controlling how a widget is run.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// The number of colors the terminal supports. If this is `None` it's detected from the
+    /// `COLORTERM` and `TERM` environment variables and the terminfo database. Colors which the
+    /// terminal doesn't support are converted to the nearest one that it does.
+    pub color_depth: Option<ColorDepth>,
+    /// Draw the UI inline, in this many rows below the cursor, instead of taking over the whole
+    /// screen. The terminal's existing output stays where it is and the last frame is left on the
+    /// screen when the widget completes. Use `print_above` to add lines to the scrollback above
+    /// the UI.
+    pub inline_height: Option<u16>,
+    /// Draw at most this many frames per second. Wake-ups which arrive sooner than that after the
+    /// last frame are coalesced into a single frame, drawn once the interval is up.
+    ///
+    /// Whether or not this is set, a new frame isn't drawn while the last one is still being
+    /// written to the terminal. Over a slow link intermediate frames get dropped rather than
+    /// queueing up.
+    pub max_frame_rate: Option<u32>,
+    /// Record the session to an asciicast v2 file at this path, which can be played back with
+    /// `asciinema play`. If this is `None`, the path is taken from the `TERMCANDY_RECORD`
+    /// environment variable, if it's set.
+    pub record_path: Option<PathBuf>,
+    /// Include the raw input read from the terminal in the recording. Recordings made because of
+    /// `TERMCANDY_RECORD` include input if `TERMCANDY_RECORD_INPUT` is set to `1`.
+    ///
+    /// Beware that input can include passwords and the like.
+    pub record_input: bool,
+    /// How to show images drawn with `SurfaceMut::draw_image`. If this is `None` it's guessed
+    /// from the environment, falling back to half-block characters.
+    pub image_protocol: Option<ImageProtocol>,
+}
+
+/// Run a widget on the given backend, returning its output once it completes.
+pub async fn run_with<B, W>(backend: B, widget: W) -> io::Result<W::Output>
+where
+    B: Backend,
+    W: Widget,
+{
+    run_with_options(backend, RunOptions::default(), widget).await
+}
+
+/// Run a widget on the given backend with the given options, returning its output once it
+/// completes.
+pub async fn run_with_options<B, W>(
+    mut backend: B,
+    options: RunOptions,
+    widget: W,
+) -> io::Result<W::Output>
+where
+    B: Backend,
+    W: Widget,
+{
+    let input = backend.take_input()?;
+    let (w, h) = backend.size()?;
+    let recorder_opt = Recorder::from_options(&backend, &options, w, h)?;
+    let input = RecordInput::new(input, recorder_opt.clone());
+    crate::input::with_input_handling(input, {
+        crate::screen::with_screen(backend, &options, recorder_opt, |screen| {
             Run {
                 screen,
                 widget,
+                frame_interval_opt: {
+                    options.max_frame_rate
+                        .filter(|&frame_rate| frame_rate > 0)
+                        .map(|frame_rate| Duration::from_secs(1) / frame_rate)
+                },
+                last_frame_opt: None,
+                frame_timer: time::sleep(Duration::from_secs(0)),
             }
         })
     }).await.flatten().flatten()
 }
 
 #[pin_project]
-pub struct Run<W> {
+pub struct Run<B: Backend, W> {
     #[pin]
-    screen: Screen,
+    screen: Screen<B>,
     #[pin]
     widget: W,
+    frame_interval_opt: Option<Duration>,
+    last_frame_opt: Option<Instant>,
+    frame_timer: Sleep,
 }
 
-impl<W: Widget> Future for Run<W> {
+impl<B: Backend, W: Widget> Future for Run<B, W> {
     type Output = io::Result<W::Output>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<W::Output>> {
         let mut this = self.project();
+        // While another program has the terminal even the widget waits, since if it finished
+        // we'd be leaving the terminal to that program.
+        match this.screen.as_mut().poll_released(cx) {
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Ok(())) => (),
+        }
         match this.widget.as_mut().poll(cx) {
             Poll::Ready(val) => return Poll::Ready(Ok(val)),
             Poll::Pending => (),
         }
 
-        // TODO: we don't handle resizing very well.
-        // The resize poller could be a separate object, outside of Screen.
-        // Also we blank and then flush the screen on a resize, causing flicker when resizing.
+        this.screen.as_mut().poll_capabilities(cx);
+        if let Err(err) = this.screen.as_mut().poll_commands(cx) {
+            return Poll::Ready(Err(err));
+        }
+        match this.screen.as_mut().poll_released(cx) {
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Ok(())) => (),
+        }
         match this.screen.as_mut().poll_for_resizes(cx) {
             Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
             Poll::Ready(Ok(_)) => (),
             Poll::Pending => (),
         }
-        match this.screen.as_mut().flush(cx) {
+        // Don't start a new frame until the last one has been written out. If the terminal can't
+        // keep up then it gets the latest frame once it's ready, rather than a backlog of old ones.
+        match this.screen.as_mut().flush_pending(cx) {
             Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
             Poll::Pending => return Poll::Pending,
             Poll::Ready(Ok(())) => (),
         }
 
+        if let Some(frame_interval) = *this.frame_interval_opt {
+            let now = Instant::now();
+            if let Some(last_frame) = *this.last_frame_opt {
+                let next_frame = last_frame + frame_interval;
+                if now < next_frame {
+                    this.frame_timer.reset(next_frame);
+                    if Pin::new(&mut *this.frame_timer).poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+            }
+            *this.last_frame_opt = Some(now);
+        }
+
         this.screen.draw_widget(this.widget.into_ref().get_ref());
 
         match this.screen.as_mut().flush(cx) {
@@ -60,3 +164,105 @@ impl<W: Widget> Future for Run<W> {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use crate::backend::MockBackend;
+    use crate::graphics::Style;
+    use crate::widget::FutureExt;
+
+    /// A widget which shows the numbers 1 to 5, one more each time it's polled, then waits for
+    /// `finish`. The number shown in each frame, and when, are pushed to `frames`.
+    fn counter<F>(
+        frames: Rc<RefCell<Vec<(Instant, u32)>>>,
+        on_count: impl Fn(u32),
+        finish: F,
+    ) -> impl Widget<Output = ()>
+    where
+        F: Future<Output = ()>,
+    {
+        let count = Rc::new(Cell::new(0));
+        let drawn_count = count.clone();
+        async move {
+            for i in 1..=5 {
+                count.set(i);
+                on_count(i);
+                tokio::task::yield_now().await;
+            }
+            finish.await;
+        }.draw_as(move |surface| {
+            frames.borrow_mut().push((Instant::now(), drawn_count.get()));
+            surface.print(&drawn_count.get().to_string(), 0, 0, Style::default());
+        })
+    }
+
+    #[tokio::test]
+    async fn frame_rate_limit() {
+        time::pause();
+        // Tokio's paused clock jumps to the next timer whenever the runtime runs out of things to
+        // do, even if a task has just been woken. Keep it busy, so that time only passes when we
+        // say so.
+        tokio::spawn(async {
+            loop {
+                tokio::task::yield_now().await;
+            }
+        });
+        let start = Instant::now();
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let widget = counter(frames.clone(), |_| (), time::sleep(Duration::from_secs(1)));
+        let options = RunOptions { max_frame_rate: Some(10), .. RunOptions::default() };
+        let run = run_with_options(MockBackend::new(4, 1), options, widget);
+        pin_utils::pin_mut!(run);
+
+        // The first frame goes out straight away. The rest of the burst is held back until the
+        // interval is up, and then only the last of it gets drawn.
+        for _ in 0..10 {
+            assert!(futures::poll!(&mut run).is_pending());
+        }
+        assert_eq!(*frames.borrow(), [(start, 1)]);
+        time::advance(Duration::from_millis(99)).await;
+        assert!(futures::poll!(&mut run).is_pending());
+        assert_eq!(*frames.borrow(), [(start, 1)]);
+        time::advance(Duration::from_millis(1)).await;
+        for _ in 0..10 {
+            assert!(futures::poll!(&mut run).is_pending());
+        }
+        assert_eq!(*frames.borrow(), [(start, 1), (start + Duration::from_millis(100), 5)]);
+
+        time::advance(Duration::from_secs(1)).await;
+        run.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn frames_skipped_while_flushing() {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let backend = MockBackend::new(4, 1);
+        let state = backend.state();
+        let stall_writes = {
+            let state = state.clone();
+            move |i| if i == 2 {
+                state.lock().unwrap().write_stalled = true;
+            }
+        };
+        let widget = counter(frames.clone(), stall_writes, futures::future::pending());
+        let run = run_with_options(backend, RunOptions::default(), widget);
+        pin_utils::pin_mut!(run);
+        let counts = || frames.borrow().iter().map(|&(_, count)| count).collect::<Vec<_>>();
+
+        // The second frame gets stuck being written, so nothing more is drawn.
+        for _ in 0..10 {
+            assert!(futures::poll!(&mut run).is_pending());
+        }
+        assert_eq!(counts(), [1, 2]);
+
+        // Once the terminal catches up it gets the latest frame.
+        state.lock().unwrap().unstall_writes();
+        assert!(futures::poll!(&mut run).is_pending());
+        assert_eq!(counts(), [1, 2, 5]);
+        assert!(String::from_utf8_lossy(&state.lock().unwrap().output).ends_with('5'));
+    }
+}
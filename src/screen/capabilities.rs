@@ -0,0 +1,377 @@
+//! Asking the terminal what it supports.
+//!
+//! Guessing from `TERM` and friends only goes so far, especially over SSH where most of the
+//! environment gets left behind. Instead, a handful of standard queries are sent when the UI
+//! starts, followed by a request for the primary device attributes (DA1), which every terminal
+//! answers. Terminals answer in the order they're asked, so once the DA1 reply is in, any query
+//! still waiting for a reply is one the terminal didn't understand.
+
+use super::*;
+
+use crate::input::{expect_reply, wait_for_reply, Reply};
+
+/// How long to wait for the terminal to answer before deciding that it never will.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Primary device attributes.
+const DA1: &[u8] = b"\x1b[c";
+
+/// What the terminal has said about itself. See `termcandy::capabilities`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the terminal answered at all. If it didn't, the rest of this is left at its
+    /// defaults, which isn't to say that the terminal doesn't support those things.
+    pub responded: bool,
+    /// The terminal's name and version, eg. `kitty(0.31.0)` or `XTerm(388)`, from XTVERSION.
+    pub version: Option<String>,
+    /// The primary device attributes (DA1): a conformance level, then the extensions supported.
+    pub primary_attributes: Vec<u16>,
+    /// The secondary device attributes (DA2): the terminal type, then a firmware version and a
+    /// third number, whose meanings vary between terminals.
+    pub secondary_attributes: Vec<u16>,
+    /// Whether the terminal can show sixel graphics, according to DA1.
+    pub sixel: bool,
+    /// Whether the terminal supports synchronized updates (DEC private mode 2026), according to
+    /// DECRQM. `None` if it didn't say.
+    pub synchronized_output: Option<bool>,
+    /// Whether the terminal supports the kitty keyboard protocol.
+    pub kitty_keyboard: bool,
+    /// Whether the terminal supports 24-bit colour, according to the `RGB` and `Tc` capabilities
+    /// it reports with XTGETTCAP. `None` if it didn't say.
+    pub true_color: Option<bool>,
+    /// The terminal's default foreground colour, as a `Color::Rgb`, from OSC 10.
+    pub foreground: Option<Color>,
+    /// The terminal's default background colour, as a `Color::Rgb`, from OSC 11.
+    pub background: Option<Color>,
+}
+
+impl Capabilities {
+    /// Whether the terminal's background is dark, so that light text will show up best on it.
+    /// `None` if the terminal didn't say what its background colour is.
+    pub fn is_dark_background(&self) -> Option<bool> {
+        self.background.and_then(Color::is_dark)
+    }
+}
+
+/// The settings which were guessed from the environment rather than set explicitly, and can be
+/// corrected once the terminal has said what it supports.
+pub(super) struct Guessed {
+    pub color_depth: bool,
+    pub image_protocol: bool,
+    pub synchronized_output: bool,
+}
+
+/// Finding out what the terminal supports.
+pub(super) enum Probe {
+    /// Waiting for the terminal to answer, along with everyone who's asked for the answer.
+    Running(Pin<Box<dyn Future<Output = Capabilities> + Send>>, Vec<oneshot::Sender<Capabilities>>),
+    Done(Capabilities),
+}
+
+/// The queries which are sent before DA1.
+#[derive(Clone, Copy)]
+enum Query {
+    Version,
+    SecondaryAttributes,
+    SynchronizedOutput,
+    KittyKeyboard,
+    Rgb,
+    Tc,
+    Foreground,
+    Background,
+}
+
+impl Query {
+    const ALL: [Query; 8] = [
+        Query::Version,
+        Query::SecondaryAttributes,
+        Query::SynchronizedOutput,
+        Query::KittyKeyboard,
+        Query::Rgb,
+        Query::Tc,
+        Query::Foreground,
+        Query::Background,
+    ];
+
+    fn sequence(self) -> &'static [u8] {
+        match self {
+            Query::Version => b"\x1b[>0q",
+            Query::SecondaryAttributes => b"\x1b[>c",
+            Query::SynchronizedOutput => b"\x1b[?2026$p",
+            Query::KittyKeyboard => b"\x1b[?u",
+            // XTGETTCAP takes the names of the capabilities in hex.
+            Query::Rgb => b"\x1bP+q524742\x1b\\",
+            Query::Tc => b"\x1bP+q5463\x1b\\",
+            Query::Foreground => b"\x1b]10;?\x1b\\",
+            Query::Background => b"\x1b]11;?\x1b\\",
+        }
+    }
+
+    fn matches(self, reply: &Reply) -> bool {
+        match (self, reply) {
+            (Query::Version, Reply::Dcs(dcs)) => dcs.starts_with(b">|"),
+            (Query::SecondaryAttributes, Reply::Csi(csi)) => {
+                csi.starts_with(b">") && csi.ends_with(b"c")
+            },
+            (Query::SynchronizedOutput, Reply::Csi(csi)) => {
+                csi.starts_with(b"?2026;") && csi.ends_with(b"$y")
+            },
+            (Query::KittyKeyboard, Reply::Csi(csi)) => csi.starts_with(b"?") && csi.ends_with(b"u"),
+            (Query::Rgb, Reply::Dcs(dcs)) | (Query::Tc, Reply::Dcs(dcs)) => {
+                dcs.starts_with(b"1+r") || dcs.starts_with(b"0+r")
+            },
+            (Query::Foreground, Reply::Osc(osc)) => osc.starts_with(b"10;"),
+            (Query::Background, Reply::Osc(osc)) => osc.starts_with(b"11;"),
+            _ => false,
+        }
+    }
+}
+
+/// Ask the terminal what it supports.
+///
+/// If `only_da1` is set then only DA1 is sent. Terminals which don't understand a query are
+/// meant to ignore it, but some (such as the Linux console) print part of it instead.
+pub(super) async fn probe(only_da1: bool) -> Capabilities {
+    let queries: &[Query] = if only_da1 { &[] } else { &Query::ALL };
+    let mut receivers = Vec::with_capacity(queries.len());
+    for &query in queries {
+        match expect_reply(Box::new(move |reply| query.matches(reply))) {
+            Some(receiver) => receivers.push((query, receiver)),
+            None => return Capabilities::default(),
+        }
+    }
+    let da1_filter = Box::new(|reply: &Reply| match reply {
+        Reply::Csi(csi) => csi.starts_with(b"?") && csi.ends_with(b"c"),
+        _ => false,
+    });
+    let da1_receiver = match expect_reply(da1_filter) {
+        Some(receiver) => receiver,
+        None => return Capabilities::default(),
+    };
+    let mut sequence = Vec::new();
+    for query in queries {
+        sequence.extend_from_slice(query.sequence());
+    }
+    sequence.extend_from_slice(DA1);
+    if !write_sequence(sequence) {
+        return Capabilities::default();
+    }
+
+    let da1 = match wait_for_reply(da1_receiver, PROBE_TIMEOUT).await {
+        Some(Reply::Csi(csi)) => csi,
+        _ => return Capabilities::default(),
+    };
+    let mut capabilities = Capabilities::from_primary_attributes(&da1);
+    for (query, mut receiver) in receivers {
+        // Anything the terminal was going to answer has been answered by now.
+        match receiver.try_recv() {
+            Ok(Some(reply)) => capabilities.apply_reply(query, reply),
+            Ok(None) | Err(..) => continue,
+        }
+    }
+    capabilities
+}
+
+impl Capabilities {
+    /// Start from the terminal's reply to DA1, `CSI ? Ps ; ... c` without the `CSI`.
+    fn from_primary_attributes(da1: &[u8]) -> Capabilities {
+        let primary_attributes = parse_params(&da1[1..(da1.len() - 1)]);
+        Capabilities {
+            responded: true,
+            // The first attribute is the conformance level. Extension 4 is sixel graphics.
+            sixel: primary_attributes.iter().skip(1).any(|&attribute| attribute == 4),
+            primary_attributes,
+            ..Capabilities::default()
+        }
+    }
+
+    /// Fill in what the terminal said in reply to `query`.
+    fn apply_reply(&mut self, query: Query, reply: Reply) {
+        match (query, reply) {
+            (Query::Version, Reply::Dcs(dcs)) => {
+                self.version = Some(String::from_utf8_lossy(&dcs[2..]).into_owned());
+            },
+            (Query::SecondaryAttributes, Reply::Csi(csi)) => {
+                self.secondary_attributes = parse_params(&csi[1..(csi.len() - 1)]);
+            },
+            (Query::SynchronizedOutput, Reply::Csi(csi)) => {
+                // The mode's setting: 1 or 2 if it can be set and reset, 3 if it's permanently
+                // set, 4 if it's permanently reset and 0 if the terminal doesn't know it.
+                let params = parse_params(&csi[1..(csi.len() - 2)]);
+                let setting = params.get(1).cloned().unwrap_or(0);
+                self.synchronized_output = Some((1..=3).contains(&setting));
+            },
+            (Query::KittyKeyboard, Reply::Csi(..)) => self.kitty_keyboard = true,
+            (Query::Rgb, Reply::Dcs(dcs)) | (Query::Tc, Reply::Dcs(dcs)) => {
+                let supported = dcs.starts_with(b"1+r");
+                self.true_color = Some(self.true_color == Some(true) || supported);
+            },
+            // The reply repeats the query with the `?` replaced by the colour.
+            (Query::Foreground, Reply::Osc(osc)) => self.foreground = parse_color(&osc[3..]),
+            (Query::Background, Reply::Osc(osc)) => self.background = parse_color(&osc[3..]),
+            _ => (),
+        }
+    }
+}
+
+/// Parse a colour in one of the X11 formats terminals reply with: `rgb:R/G/B`, where each part is
+/// 1 to 4 hex digits, or `#RGB`, `#RRGGBB` and so on.
+fn parse_color(spec: &[u8]) -> Option<Color> {
+    let spec = std::str::from_utf8(spec).ok()?;
+    // Terminals give up to 16 bits per component, which gets scaled down to 8.
+    let component = |hex: &str| -> Option<u8> {
+        if hex.is_empty() || hex.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        let max = (1u32 << (4 * hex.len())) - 1;
+        Some((value * 255 / max) as u8)
+    };
+    let (r, g, b) = if let Some(rgb) = spec.strip_prefix("rgb:") {
+        let mut parts = rgb.split('/');
+        let r = component(parts.next()?)?;
+        let g = component(parts.next()?)?;
+        let b = component(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        (r, g, b)
+    } else {
+        let hex = spec.strip_prefix('#')?;
+        let len = hex.len() / 3;
+        if len == 0 || hex.len() % 3 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        (component(&hex[..len])?, component(&hex[len..(2 * len)])?, component(&hex[(2 * len)..])?)
+    };
+    Some(Color::Rgb { r, g, b })
+}
+
+/// Parse the numeric parameters of a control sequence. Missing or malformed ones are zero.
+fn parse_params(params: &[u8]) -> Vec<u16> {
+    params
+        .split(|&b| b == b';')
+        .map(|param| {
+            param.iter().try_fold(0u16, |n, &b| {
+                if b.is_ascii_digit() {
+                    Some(n.saturating_mul(10).saturating_add((b - b'0') as u16))
+                } else {
+                    None
+                }
+            }).unwrap_or(0)
+        })
+        .collect()
+}
+
+impl Buffers {
+    /// Correct the settings that were guessed from the environment, now that the terminal has
+    /// said what it supports.
+    pub(super) fn apply_capabilities(&mut self, capabilities: &Capabilities, guessed: &Guessed) {
+        if guessed.synchronized_output {
+            if let Some(synchronized_output) = capabilities.synchronized_output {
+                self.synchronized_output = synchronized_output;
+            }
+        }
+        let true_color = guessed.color_depth && capabilities.true_color == Some(true);
+        if true_color && self.color_depth != ColorDepth::TrueColor {
+            self.color_depth = ColorDepth::TrueColor;
+            self.damaged = true;
+        }
+        let sixel = guessed.image_protocol && capabilities.sixel;
+        if sixel && self.image_protocol == ImageProtocol::HalfBlocks {
+            self.image_protocol = ImageProtocol::Sixel;
+            self.damaged = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn params() {
+        assert_eq!(parse_params(b"62;4;22"), vec![62, 4, 22]);
+        assert_eq!(parse_params(b""), vec![0]);
+        assert_eq!(parse_params(b"1;;x3;7"), vec![1, 0, 0, 7]);
+        assert_eq!(parse_params(b"99999999"), vec![u16::MAX]);
+    }
+
+    #[test]
+    fn color() {
+        let rgb = |r, g, b| Some(Color::Rgb { r, g, b });
+        // Each component is scaled from however many digits it has.
+        assert_eq!(parse_color(b"rgb:f/8/0"), rgb(255, 136, 0));
+        assert_eq!(parse_color(b"rgb:ff/80/00"), rgb(255, 128, 0));
+        assert_eq!(parse_color(b"rgb:fff/800/000"), rgb(255, 127, 0));
+        assert_eq!(parse_color(b"rgb:ffff/8000/0000"), rgb(255, 127, 0));
+        assert_eq!(parse_color(b"rgb:1e1e/1e1e/2e2e"), rgb(30, 30, 46));
+        // The digits don't have to be the same length.
+        assert_eq!(parse_color(b"rgb:ff/8/0000"), rgb(255, 136, 0));
+        assert_eq!(parse_color(b"#f80"), rgb(255, 136, 0));
+        assert_eq!(parse_color(b"#ff8000"), rgb(255, 128, 0));
+        assert_eq!(parse_color(b"#ffff80000000"), rgb(255, 127, 0));
+
+        assert_eq!(parse_color(b"rgb:ff/80"), None);
+        assert_eq!(parse_color(b"rgb:ff/80/00/00"), None);
+        assert_eq!(parse_color(b"rgb:fffff/0/0"), None);
+        assert_eq!(parse_color(b"rgb:ff//00"), None);
+        assert_eq!(parse_color(b"rgb:gg/00/00"), None);
+        assert_eq!(parse_color(b"#ff80"), None);
+        assert_eq!(parse_color(b"#"), None);
+        assert_eq!(parse_color(b"#\xc3\xa9\xc3\xa9\xc3\xa9"), None);
+        assert_eq!(parse_color(b"ff8000"), None);
+        assert_eq!(parse_color(b"\xff"), None);
+    }
+
+    #[test]
+    fn primary_attributes() {
+        let capabilities = Capabilities::from_primary_attributes(b"?62;4;22c");
+        assert!(capabilities.responded);
+        assert_eq!(capabilities.primary_attributes, vec![62, 4, 22]);
+        assert!(capabilities.sixel);
+
+        // A conformance level of 4 isn't sixel support.
+        let capabilities = Capabilities::from_primary_attributes(b"?4;6c");
+        assert_eq!(capabilities.primary_attributes, vec![4, 6]);
+        assert!(!capabilities.sixel);
+    }
+
+    #[test]
+    fn synchronized_output() {
+        let setting = |reply: &[u8]| {
+            let mut capabilities = Capabilities::default();
+            capabilities.apply_reply(Query::SynchronizedOutput, Reply::Csi(reply.to_vec()));
+            capabilities.synchronized_output
+        };
+        assert_eq!(setting(b"?2026;1$y"), Some(true));
+        assert_eq!(setting(b"?2026;2$y"), Some(true));
+        assert_eq!(setting(b"?2026;3$y"), Some(true));
+        assert_eq!(setting(b"?2026;4$y"), Some(false));
+        assert_eq!(setting(b"?2026;0$y"), Some(false));
+        assert_eq!(setting(b"?2026$y"), Some(false));
+    }
+
+    #[test]
+    fn replies() {
+        let mut capabilities = Capabilities::default();
+        capabilities.apply_reply(Query::Version, Reply::Dcs(b">|kitty(0.31.0)".to_vec()));
+        capabilities.apply_reply(Query::SecondaryAttributes, Reply::Csi(b">1;4000;29c".to_vec()));
+        capabilities.apply_reply(Query::KittyKeyboard, Reply::Csi(b"?0u".to_vec()));
+        capabilities.apply_reply(Query::Rgb, Reply::Dcs(b"0+r524742".to_vec()));
+        capabilities.apply_reply(Query::Tc, Reply::Dcs(b"1+r5463".to_vec()));
+        let background = Reply::Osc(b"11;rgb:0000/0000/0000".to_vec());
+        capabilities.apply_reply(Query::Background, background);
+        // Replies to some other query are ignored.
+        capabilities.apply_reply(Query::Foreground, Reply::Csi(b"?0u".to_vec()));
+        assert_eq!(capabilities, Capabilities {
+            version: Some("kitty(0.31.0)".to_owned()),
+            secondary_attributes: vec![1, 4000, 29],
+            kitty_keyboard: true,
+            true_color: Some(true),
+            background: Some(Color::Rgb { r: 0, g: 0, b: 0 }),
+            ..Capabilities::default()
+        });
+        assert_eq!(capabilities.is_dark_background(), Some(true));
+    }
+}
@@ -0,0 +1,350 @@
+//! Choosing the cheapest way to move the cursor.
+//!
+//! There are a lot of ways to move the terminal's cursor from one place to another: absolute
+//! addressing, relative movements, carriage returns and line feeds, or just re-printing whatever is
+//! already on the screen in between. Cursor movement makes up a large part of the output when only
+//! a few scattered cells change, so it pays to pick whichever way takes the fewest bytes.
+
+use super::*;
+
+/// How to move the cursor between rows.
+#[derive(Clone, Copy)]
+enum Vertical {
+    Stay,
+    LineFeeds(u16),
+    Down(u16),
+    ReverseIndex,
+    Up(u16),
+    Row(u16),
+}
+
+/// How to move the cursor along a row.
+#[derive(Clone, Copy)]
+enum Horizontal {
+    Stay,
+    CarriageReturn,
+    Backspaces(u16),
+    Left(u16),
+    Right(u16),
+    Rewrite(u16, u16),
+    Column(u16),
+}
+
+impl Buffers {
+    /// Show the cursor where the back buffer asks for it, or hide it if it doesn't.
+    pub(super) fn place_cursor(&mut self) {
+        let cursor = match self.back_buffer.cursor() {
+            Some(cursor) => cursor,
+            None => {
+                if self.cursor_visible {
+                    self.writing.extend_from_slice(HIDE_CURSOR);
+                    self.cursor_visible = false;
+                }
+                return;
+            },
+        };
+        if self.cursor_style_opt != Some(cursor.style) {
+            // DECSCUSR
+            let shape = match cursor.style.kind {
+                CursorKind::Block => 1,
+                CursorKind::Underline => 3,
+                CursorKind::Bar => 5,
+            };
+            let shape = if cursor.style.blink { shape } else { shape + 1 };
+            write!(&mut self.writing, "\x1b[{} q", shape).unwrap();
+            self.cursor_style_opt = Some(cursor.style);
+        }
+        if self.cursor_x >= self.front_buffer.width() {
+            // Get out of the pending wrap state now. move_cursor would otherwise leave it to the
+            // next character printed, but a visible cursor needs to actually be in place.
+            self.writing.push(b'\r');
+            self.cursor_x = 0;
+        }
+        self.move_cursor(cursor.x, cursor.y);
+        if !self.cursor_visible {
+            self.writing.extend_from_slice(SHOW_CURSOR);
+            self.cursor_visible = true;
+        }
+    }
+
+    /// Move the cursor to (x, y), in preparation for printing a character there.
+    pub(super) fn move_cursor(&mut self, x: u16, y: u16) {
+        if x == self.cursor_x && y == self.cursor_y {
+            return;
+        }
+
+        let w = self.front_buffer.width();
+        if self.cursor_x >= w {
+            // We printed to the last column, so the terminal is in the "pending wrap" state. The
+            // next character printed will land at the start of the next row. Relative movements
+            // start from the last column though, so get out of this state with a carriage return.
+            if x == 0 && y == self.cursor_y + 1 {
+                self.cursor_x = x;
+                self.cursor_y = y;
+                return;
+            }
+            self.writing.push(b'\r');
+            self.cursor_x = 0;
+            if x == 0 && y == self.cursor_y {
+                return;
+            }
+        }
+
+        let (vertical, vertical_cost) = self.plan_vertical(y);
+        let (carriage_return, horizontal, horizontal_cost) = self.plan_horizontal(x, y);
+        // Inline mode doesn't know which rows of the screen it's on, so it can't use absolute rows.
+        if !self.inline && goto_cost(x, y) <= vertical_cost + horizontal_cost {
+            write_goto(&mut self.writing, x, y);
+        } else {
+            self.write_vertical(vertical);
+            if carriage_return {
+                self.writing.push(b'\r');
+            }
+            self.write_horizontal(horizontal, y);
+        }
+        self.cursor_x = x;
+        self.cursor_y = y;
+    }
+
+    fn plan_vertical(&self, y: u16) -> (Vertical, usize) {
+        let mut best = (Vertical::Row(y), column_or_row_cost(y));
+        let candidate = if y > self.cursor_y {
+            let n = y - self.cursor_y;
+            if (n as usize) < csi_cost(n) {
+                (Vertical::LineFeeds(n), n as usize)
+            } else {
+                (Vertical::Down(n), csi_cost(n))
+            }
+        } else if y < self.cursor_y {
+            let n = self.cursor_y - y;
+            if n == 1 {
+                (Vertical::ReverseIndex, 2)
+            } else {
+                (Vertical::Up(n), csi_cost(n))
+            }
+        } else {
+            (Vertical::Stay, 0)
+        };
+        if self.inline || candidate.1 <= best.1 {
+            best = candidate;
+        }
+        best
+    }
+
+    /// Plan how to get from the cursor's column to column `x` of row `y`. Returns whether to start
+    /// with a carriage return, the movement to make after that, and the total cost.
+    fn plan_horizontal(&self, x: u16, y: u16) -> (bool, Horizontal, usize) {
+        let mut best = (false, Horizontal::Column(x), column_or_row_cost(x));
+        let mut consider = |candidate: (bool, Horizontal, usize)| {
+            if candidate.2 <= best.2 {
+                best = candidate;
+            }
+        };
+
+        if x == 0 {
+            consider((false, Horizontal::CarriageReturn, 1));
+        } else {
+            consider((true, Horizontal::Right(x), 1 + csi_cost(x)));
+            if let Some(cost) = self.rewrite_cost(0, x, y) {
+                consider((true, Horizontal::Rewrite(0, x), 1 + cost));
+            }
+        }
+
+        let cursor_x = self.cursor_x;
+        if x == cursor_x {
+            consider((false, Horizontal::Stay, 0));
+        } else if x > cursor_x {
+            let n = x - cursor_x;
+            consider((false, Horizontal::Right(n), csi_cost(n)));
+            if let Some(cost) = self.rewrite_cost(cursor_x, x, y) {
+                consider((false, Horizontal::Rewrite(cursor_x, x), cost));
+            }
+        } else {
+            let n = cursor_x - x;
+            consider((false, Horizontal::Backspaces(n), n as usize));
+            consider((false, Horizontal::Left(n), csi_cost(n)));
+        }
+        best
+    }
+
+    /// The number of bytes needed to move the cursor from `x0` to `x1` on row `y` by re-printing
+    /// the cells in between. Returns `None` if they can't be re-printed without changing what's on
+    /// the screen.
+    fn rewrite_cost(&self, x0: u16, x1: u16, y: u16) -> Option<usize> {
+        if self.damaged {
+            // The front buffer doesn't match what's on the screen.
+            return None;
+        }
+        let mut cost = 0;
+        for x in x0..x1 {
+            let cell = self.front_buffer.cell(x, y);
+            if cell.style != self.current_style || cell.c.width() != 1 {
+                return None;
+            }
+            if self.front_buffer.images().iter().any(|placement| placement.covers(x, y)) {
+                // Re-printing the cell would rub out part of the image.
+                return None;
+            }
+            cost += cell.c.for_terminal().len();
+        }
+        Some(cost)
+    }
+
+    fn write_vertical(&mut self, vertical: Vertical) {
+        match vertical {
+            Vertical::Stay => (),
+            Vertical::LineFeeds(n) => {
+                for _ in 0..n {
+                    self.writing.push(b'\n');
+                }
+            },
+            Vertical::Down(n) => write_csi(&mut self.writing, n, 'B'),
+            Vertical::ReverseIndex => self.writing.extend_from_slice(b"\x1bM"),
+            Vertical::Up(n) => write_csi(&mut self.writing, n, 'A'),
+            Vertical::Row(y) => write_csi(&mut self.writing, y + 1, 'd'),
+        }
+    }
+
+    fn write_horizontal(&mut self, horizontal: Horizontal, y: u16) {
+        match horizontal {
+            Horizontal::Stay => (),
+            Horizontal::CarriageReturn => self.writing.push(b'\r'),
+            Horizontal::Backspaces(n) => {
+                for _ in 0..n {
+                    self.writing.push(b'\x08');
+                }
+            },
+            Horizontal::Left(n) => write_csi(&mut self.writing, n, 'D'),
+            Horizontal::Right(n) => write_csi(&mut self.writing, n, 'C'),
+            Horizontal::Rewrite(x0, x1) => {
+                for x in x0..x1 {
+                    let c = self.front_buffer.cell(x, y).c;
+                    self.writing.extend_from_slice(c.for_terminal().as_bytes());
+                }
+            },
+            Horizontal::Column(x) => write_csi(&mut self.writing, x + 1, 'G'),
+        }
+    }
+}
+
+/// The number of decimal digits in `n`.
+fn digits(n: u16) -> usize {
+    match n {
+        0..=9 => 1,
+        10..=99 => 2,
+        100..=999 => 3,
+        1000..=9999 => 4,
+        _ => 5,
+    }
+}
+
+/// The length of a control sequence with a single numeric parameter, eg. `ESC [ n C`. A parameter
+/// of 1 is the default and can be omitted.
+fn csi_cost(n: u16) -> usize {
+    if n == 1 {
+        3
+    } else {
+        3 + digits(n)
+    }
+}
+
+/// The cost of moving to an absolute (zero-based) row or column.
+fn column_or_row_cost(n: u16) -> usize {
+    csi_cost(n + 1)
+}
+
+/// The cost of moving to an absolute position with `write_goto`.
+fn goto_cost(x: u16, y: u16) -> usize {
+    match (x, y) {
+        (0, 0) => 3,
+        (0, y) => 3 + digits(y + 1),
+        (x, y) => 4 + digits(y + 1) + digits(x + 1),
+    }
+}
+
+fn write_csi(writing: &mut Vec<u8>, n: u16, command: char) {
+    if n == 1 {
+        write!(writing, "\x1b[{}", command).unwrap();
+    } else {
+        write!(writing, "\x1b[{}{}", n, command).unwrap();
+    }
+}
+
+/// Move to an absolute (zero-based) position, omitting default parameters.
+pub(super) fn write_goto(writing: &mut Vec<u8>, x: u16, y: u16) {
+    match (x, y) {
+        (0, 0) => write!(writing, "\x1b[H").unwrap(),
+        (0, y) => write!(writing, "\x1b[{}H", y + 1).unwrap(),
+        (x, y) => write!(writing, "\x1b[{};{}H", y + 1, x + 1).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::graphics::Attrs;
+
+    fn move_from(buffers: &mut Buffers, from: (u16, u16), to: (u16, u16)) -> Vec<u8> {
+        buffers.cursor_x = from.0;
+        buffers.cursor_y = from.1;
+        buffers.writing.clear();
+        buffers.move_cursor(to.0, to.1);
+        assert_eq!((buffers.cursor_x, buffers.cursor_y), to);
+        buffers.writing.clone()
+    }
+
+    #[test]
+    fn short_moves_use_relative_sequences() {
+        let mut buffers = Buffers::blank(80, 24);
+        assert_eq!(move_from(&mut buffers, (5, 3), (5, 3)), b"");
+        assert_eq!(move_from(&mut buffers, (5, 3), (3, 3)), b"\x08\x08");
+        assert_eq!(move_from(&mut buffers, (5, 3), (5, 2)), b"\x1bM");
+        assert_eq!(move_from(&mut buffers, (5, 3), (5, 5)), b"\n\n");
+        assert_eq!(move_from(&mut buffers, (5, 3), (0, 4)), b"\n\r");
+        assert_eq!(move_from(&mut buffers, (5, 3), (5, 10)), b"\x1b[7B");
+    }
+
+    #[test]
+    fn long_moves_use_absolute_position() {
+        let mut buffers = Buffers::blank(80, 24);
+        assert_eq!(move_from(&mut buffers, (0, 0), (40, 20)), b"\x1b[21;41H");
+        assert_eq!(move_from(&mut buffers, (40, 20), (0, 0)), b"\x1b[H");
+        assert_eq!(move_from(&mut buffers, (40, 20), (40, 2)), b"\x1b[3d");
+    }
+
+    #[test]
+    fn rewrites_unchanged_cells_when_cheaper() {
+        let mut buffers = Buffers::blank(80, 24);
+        buffers.front_buffer.print("abcdef", 0, 0, Style::default());
+        assert_eq!(move_from(&mut buffers, (0, 0), (3, 0)), b"abc");
+
+        // Re-printing cells in another style would need SGR changes, so move over them instead.
+        let bold = Style { attrs: Attrs { bold: true, ..Attrs::default() }, ..Style::default() };
+        buffers.front_buffer.print("abc", 0, 1, bold);
+        assert_eq!(move_from(&mut buffers, (0, 1), (3, 1)), b"\x1b[3C");
+
+        // The front buffer can't be trusted once the screen is damaged.
+        buffers.damaged = true;
+        assert_eq!(move_from(&mut buffers, (0, 0), (3, 0)), b"\x1b[3C");
+    }
+
+    #[test]
+    fn pending_wrap() {
+        let mut buffers = Buffers::blank(10, 5);
+        // Printing to the last column leaves the cursor there, waiting to wrap.
+        assert_eq!(move_from(&mut buffers, (10, 1), (0, 2)), b"");
+        assert_eq!(move_from(&mut buffers, (10, 1), (0, 1)), b"\r");
+        // Relative movements would start from the last column rather than past it.
+        assert_eq!(move_from(&mut buffers, (10, 1), (8, 1)), b"\r\x1b[8C");
+        assert_eq!(move_from(&mut buffers, (10, 1), (9, 3)), b"\r\n\n\x1b[9C");
+    }
+
+    #[test]
+    fn inline_mode_never_uses_absolute_rows() {
+        let mut buffers = Buffers::blank(80, 24);
+        buffers.inline = true;
+        assert_eq!(move_from(&mut buffers, (0, 0), (40, 20)), b"\x1b[20B\x1b[40C");
+        assert_eq!(move_from(&mut buffers, (40, 20), (40, 2)), b"\x1b[18A");
+    }
+}
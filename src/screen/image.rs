@@ -0,0 +1,514 @@
+//! Showing images on the terminal.
+//!
+//! Images are drawn after the cells of a frame, over the top of them. The cells under an image
+//! are left alone for as long as the same image stays in the same place, since on most terminals
+//! writing to them would rub the image out. When an image goes away, the cells it covered are
+//! redrawn, or for kitty (which keeps images separate from text) the image is deleted.
+
+use super::*;
+
+use crate::base64;
+use crate::graphics::{scale_nearest, ImagePlacement, ImageProtocol, Rect, RgbaImage};
+
+/// The size of a cell in pixels, if the terminal won't tell us.
+pub(super) const DEFAULT_CELL_SIZE: (u32, u32) = (10, 20);
+
+/// The most base64 data kitty accepts in a single escape sequence.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// How a cell should be updated, given the images on the screen.
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum CellUpdate {
+    /// Draw the cell if it's changed.
+    Diff,
+    /// Draw the cell whether or not it's changed, since an image was rubbed out from over it.
+    Redraw,
+    /// Leave the cell alone, since it's under an image which is staying put.
+    Covered,
+}
+
+/// What to do about images while drawing a frame.
+pub(super) struct ImagePlan {
+    /// A `CellUpdate` for every cell, row by row. Empty if there are no images to worry about.
+    cells: Vec<CellUpdate>,
+    width: u16,
+    /// The indices of the back buffer's images which need to be drawn.
+    draw: Vec<usize>,
+    /// The kitty image IDs of the back buffer's images, for those already on screen.
+    kitty_ids: Vec<Option<u32>>,
+}
+
+impl ImagePlan {
+    pub fn cell_update(&self, x: u16, y: u16) -> CellUpdate {
+        if self.cells.is_empty() {
+            return CellUpdate::Diff;
+        }
+        self.cells[y as usize * self.width as usize + x as usize]
+    }
+}
+
+impl Buffers {
+    /// Whether either buffer has images on it, in which case the cells can't be scrolled.
+    pub(super) fn has_images(&self) -> bool {
+        !self.front_buffer.images().is_empty() || !self.back_buffer.images().is_empty()
+    }
+
+    /// Work out which images need to be drawn and rubbed out, and delete the ones which kitty is
+    /// showing but shouldn't be. This happens before the cells are drawn.
+    pub(super) fn plan_images(&mut self, invalid: &[Rect]) -> ImagePlan {
+        if self.image_protocol == ImageProtocol::HalfBlocks {
+            self.draw_half_block_images();
+        }
+        let w = self.back_buffer.width();
+        let h = self.back_buffer.height();
+        let mut plan = ImagePlan {
+            cells: Vec::new(),
+            width: w,
+            draw: Vec::new(),
+            kitty_ids: Vec::new(),
+        };
+        if !self.has_images() {
+            return plan;
+        }
+        plan.cells = vec![CellUpdate::Diff; w as usize * h as usize];
+
+        let front_images = self.front_buffer.take_images();
+        let kitty_ids = mem::take(&mut self.kitty_ids);
+        let mut kept = vec![false; front_images.len()];
+        for (index, placement) in self.back_buffer.images().iter().enumerate() {
+            let invalidated = invalid.iter().any(|rect| rect.intersects(&placement.visible));
+            let shown_opt = if self.damaged || invalidated {
+                None
+            } else {
+                front_images.iter().enumerate().position(|(i, shown)| {
+                    !kept[i] && shown.same_as(placement)
+                })
+            };
+            match shown_opt {
+                Some(i) => {
+                    kept[i] = true;
+                    plan.kitty_ids.push(kitty_ids.get(i).cloned().flatten());
+                    mark_cells(&mut plan, placement, CellUpdate::Covered);
+                },
+                None => {
+                    plan.kitty_ids.push(None);
+                    plan.draw.push(index);
+                },
+            }
+        }
+        for (i, shown) in front_images.iter().enumerate() {
+            if kept[i] {
+                continue;
+            }
+            match kitty_ids.get(i).cloned().flatten() {
+                Some(id) => write!(&mut self.writing, "\x1b_Ga=d,d=I,i={},q=2\x1b\\", id).unwrap(),
+                None => mark_cells(&mut plan, shown, CellUpdate::Redraw),
+            }
+        }
+        plan
+    }
+
+    /// Draw the images which aren't on the screen yet. This happens after the cells are drawn.
+    pub(super) fn draw_images(&mut self, mut plan: ImagePlan) {
+        for &index in &plan.draw {
+            let placement = self.back_buffer.images()[index].clone();
+            self.move_cursor(placement.visible.x0 as u16, placement.visible.y0 as u16);
+            // Save and restore the cursor, since each protocol leaves it somewhere different.
+            self.writing.extend_from_slice(b"\x1b7");
+            let (cell_w, cell_h) = self.cell_size;
+            match self.image_protocol {
+                ImageProtocol::Kitty => {
+                    let id = self.next_kitty_id;
+                    self.next_kitty_id = self.next_kitty_id.wrapping_add(1).max(1);
+                    write_kitty(&mut self.writing, &placement, id);
+                    plan.kitty_ids[index] = Some(id);
+                },
+                ImageProtocol::Sixel => write_sixel(&mut self.writing, &placement, cell_w, cell_h),
+                ImageProtocol::Iterm2 => write_iterm2(&mut self.writing, &placement, cell_w, cell_h),
+                ImageProtocol::HalfBlocks => (),
+            }
+            self.writing.extend_from_slice(b"\x1b8");
+        }
+        self.front_buffer.set_images(self.back_buffer.images().to_vec());
+        self.kitty_ids = plan.kitty_ids;
+    }
+
+    /// Forget about the images on the screen, eg. because the screen is about to be redrawn from
+    /// scratch. Images that kitty is showing are deleted.
+    pub(super) fn forget_images(&mut self) {
+        for id in mem::take(&mut self.kitty_ids).into_iter().flatten() {
+            write!(&mut self.writing, "\x1b_Ga=d,d=I,i={},q=2\x1b\\", id).unwrap();
+        }
+        self.front_buffer.take_images();
+    }
+
+    /// Replace the images on the back buffer with half-block characters, two pixels to a cell.
+    fn draw_half_block_images(&mut self) {
+        for placement in self.back_buffer.take_images() {
+            let visible = placement.visible;
+            let (cropped, _, _) = placement.visible_pixels(1, 2);
+            let w = visible.width() as u32;
+            let pixels = scale_nearest(&cropped, w, visible.height() as u32 * 2);
+            let color = |[r, g, b, a]: [u8; 4]| {
+                if a < 128 { Color::Default } else { Color::Rgb { r, g, b } }
+            };
+            for y in 0..visible.height() {
+                for x in 0..visible.width() {
+                    let top = pixels[(y as usize * 2) * w as usize + x as usize];
+                    let bottom = pixels[(y as usize * 2 + 1) * w as usize + x as usize];
+                    let cell = self.back_buffer.cell_mut(
+                        (visible.x0 + x as i16) as u16,
+                        (visible.y0 + y as i16) as u16,
+                    );
+                    cell.c = Grapheme::from('▀');
+                    cell.style = Style { fg: color(top), bg: color(bottom), ..Style::default() };
+                }
+            }
+        }
+    }
+}
+
+fn mark_cells(plan: &mut ImagePlan, placement: &ImagePlacement, update: CellUpdate) {
+    let visible = placement.visible;
+    for y in visible.y0..visible.y1 {
+        for x in visible.x0..visible.x1 {
+            let i = y as usize * plan.width as usize + x as usize;
+            if i < plan.cells.len() && plan.cells[i] != CellUpdate::Covered {
+                plan.cells[i] = update;
+            }
+        }
+    }
+}
+
+/// Guess which image protocol the terminal supports, based on the environment.
+///
+/// This can be overridden by setting `TERMCANDY_IMAGES` to `kitty`, `sixel`, `iterm2` or
+/// `blocks`.
+pub(super) fn detect_image_protocol<B: Backend>(backend: &B) -> ImageProtocol {
+    if let Some(value) = backend.env_var("TERMCANDY_IMAGES") {
+        return match &value[..] {
+            "kitty" => ImageProtocol::Kitty,
+            "sixel" => ImageProtocol::Sixel,
+            "iterm2" => ImageProtocol::Iterm2,
+            _ => ImageProtocol::HalfBlocks,
+        };
+    }
+    let term_program = backend.env_var("TERM_PROGRAM").unwrap_or_default();
+    match &term_program[..] {
+        "iTerm.app" | "WezTerm" | "mintty" => return ImageProtocol::Iterm2,
+        "ghostty" => return ImageProtocol::Kitty,
+        _ => (),
+    }
+    let term = backend.env_var("TERM").unwrap_or_default();
+    if backend.env_var("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" {
+        return ImageProtocol::Kitty;
+    }
+    if ["foot", "mlterm", "contour", "yaft"].iter().any(|prefix| term.starts_with(prefix)) {
+        return ImageProtocol::Sixel;
+    }
+    ImageProtocol::HalfBlocks
+}
+
+/// Send the whole image with the kitty graphics protocol, and show the visible part of it.
+fn write_kitty(out: &mut Vec<u8>, placement: &ImagePlacement, id: u32) {
+    let image = &placement.image;
+    let (x0, y0, x1, y1) = placement.source_rect();
+    let data = base64::encode(image.pixels());
+    let mut chunks = data.as_bytes().chunks(KITTY_CHUNK_SIZE).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = if chunks.peek().is_some() { 1 } else { 0 };
+        if first {
+            // C=1 stops kitty from moving the cursor. q=2 stops it from replying.
+            write!(
+                out,
+                "\x1b_Ga=T,f=32,s={},v={},i={},x={},y={},w={},h={},c={},r={},C=1,q=2,m={};",
+                image.width(), image.height(), id,
+                x0, y0, cmp::max(x1 - x0, 1), cmp::max(y1 - y0, 1),
+                placement.visible.width(), placement.visible.height(), more,
+            ).unwrap();
+            first = false;
+        } else {
+            write!(out, "\x1b_Gm={};", more).unwrap();
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+}
+
+/// Draw the visible part of the image as a sixel image.
+///
+/// Colors are reduced to a 6x6x6 cube, and pixels which are more than half transparent are left
+/// undrawn.
+fn write_sixel(out: &mut Vec<u8>, placement: &ImagePlacement, cell_w: u32, cell_h: u32) {
+    let (cropped, w, h) = placement.visible_pixels(cell_w, cell_h);
+    let pixels: Vec<Option<u8>> = scale_nearest(&cropped, w, h).into_iter().map(|[r, g, b, a]| {
+        if a < 128 {
+            return None;
+        }
+        let level = |v: u8| (v as u16 * 5 + 127) / 255;
+        Some((level(r) * 36 + level(g) * 6 + level(b)) as u8)
+    }).collect();
+
+    // P2=1 leaves undrawn pixels transparent.
+    write!(out, "\x1bP0;1;0q\"1;1;{};{}", w, h).unwrap();
+    let mut used = [false; 216];
+    for &pixel in pixels.iter().flatten() {
+        used[pixel as usize] = true;
+    }
+    for (index, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+        let percent = |level: usize| level * 100 / 5;
+        write!(
+            out, "#{};2;{};{};{}",
+            index, percent(index / 36), percent(index / 6 % 6), percent(index % 6),
+        ).unwrap();
+    }
+
+    let w = w as usize;
+    for band_start in (0..(h as usize)).step_by(6) {
+        if band_start > 0 {
+            // On to the next band. This isn't sent after the last band, since it would scroll
+            // the screen if the image reaches the bottom.
+            out.push(b'-');
+        }
+        let band_end = cmp::min(band_start + 6, h as usize);
+        let mut band_colors = [false; 216];
+        for y in band_start..band_end {
+            for &pixel in pixels[(y * w)..((y + 1) * w)].iter().flatten() {
+                band_colors[pixel as usize] = true;
+            }
+        }
+        let mut first = true;
+        for (color, _) in band_colors.iter().enumerate().filter(|(_, used)| **used) {
+            if !first {
+                // Back to the start of the band for the next color.
+                out.push(b'$');
+            }
+            first = false;
+            write!(out, "#{}", color).unwrap();
+            let mut run: Option<(u8, usize)> = None;
+            for x in 0..w {
+                let mut bits = 0u8;
+                for y in band_start..band_end {
+                    if pixels[y * w + x] == Some(color as u8) {
+                        bits |= 1 << (y - band_start);
+                    }
+                }
+                let sixel = 63 + bits;
+                run = match run {
+                    Some((c, n)) if c == sixel => Some((c, n + 1)),
+                    Some((c, n)) => {
+                        write_sixel_run(out, c, n);
+                        Some((sixel, 1))
+                    },
+                    None => Some((sixel, 1)),
+                };
+            }
+            if let Some((c, n)) = run {
+                // Trailing empty columns don't need to be sent.
+                if c != 63 {
+                    write_sixel_run(out, c, n);
+                }
+            }
+        }
+    }
+    out.extend_from_slice(b"\x1b\\");
+}
+
+fn write_sixel_run(out: &mut Vec<u8>, c: u8, n: usize) {
+    if n > 3 {
+        write!(out, "!{}", n).unwrap();
+        out.push(c);
+    } else {
+        for _ in 0..n {
+            out.push(c);
+        }
+    }
+}
+
+/// Draw the visible part of the image as an iTerm2 inline image, sent as a PNG.
+fn write_iterm2(out: &mut Vec<u8>, placement: &ImagePlacement, cell_w: u32, cell_h: u32) {
+    let (cropped, _, _) = placement.visible_pixels(cell_w, cell_h);
+    let png = encode_png(&cropped);
+    write!(
+        out,
+        "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=0:",
+        png.len(), placement.visible.width(), placement.visible.height(),
+    ).unwrap();
+    out.extend_from_slice(base64::encode(&png).as_bytes());
+    out.push(0x07);
+}
+
+/// Encode an image as a PNG. The image data is stored uncompressed, since it only has to make it
+/// as far as the terminal.
+fn encode_png(image: &RgbaImage) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((image.width() as usize * 4 + 1) * image.height() as usize);
+    for row in image.pixels().chunks(image.width() as usize * 4) {
+        // Filter type 0: none.
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // A zlib stream made of stored deflate blocks.
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        zlib.push(if last { 1 } else { 0 });
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&image.width().to_be_bytes());
+    ihdr.extend_from_slice(&image.height().to_be_bytes());
+    // 8 bits per channel, RGBA, default compression, filtering and no interlacing.
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_png_chunk(&mut png, b"IHDR", &ihdr);
+    write_png_chunk(&mut png, b"IDAT", &zlib);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn placement(width: u32, height: u32, pixels: &[[u8; 4]]) -> ImagePlacement {
+        let image = RgbaImage::new(width, height, pixels.concat());
+        let rect = Rect { x0: 0, x1: 1, y0: 0, y1: 1 };
+        ImagePlacement { image, rect, visible: rect }
+    }
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    /// A 1x1 red PNG.
+    const RED_PNG: &[u8] = b"\
+        \x89PNG\r\n\x1a\n\
+        \x00\x00\x00\x0dIHDR\x00\x00\x00\x01\x00\x00\x00\x01\x08\x06\x00\x00\x00\x1f\x15\xc4\x89\
+        \x00\x00\x00\x10IDAT\
+        \x78\x01\x01\x05\x00\xfa\xff\x00\xff\x00\x00\xff\x05\x00\x01\xff\xfa\x5c\x88\xd1\
+        \x00\x00\x00\x00IEND\xae\x42\x60\x82";
+
+    #[test]
+    fn kitty() {
+        let mut out = Vec::new();
+        write_kitty(&mut out, &placement(1, 1, &[RED]), 7);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b_Ga=T,f=32,s=1,v=1,i=7,x=0,y=0,w=1,h=1,c=1,r=1,C=1,q=2,m=0;/wAA/w==\x1b\\",
+        );
+    }
+
+    #[test]
+    fn kitty_chunks() {
+        // 8KiB of pixels is just over 10KiB of base64, so three chunks.
+        let mut out = Vec::new();
+        write_kitty(&mut out, &placement(64, 32, &[RED; 64 * 32]), 1);
+        let out = String::from_utf8(out).unwrap();
+        let chunks: Vec<&str> = out.split_terminator("\x1b\\").collect();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with("\x1b_Ga=T,f=32,s=64,v=32,i=1,"));
+        let data = |chunk: &str, prefix: &str| chunk.split(prefix).nth(1).unwrap().len();
+        assert_eq!(data(chunks[0], ",C=1,q=2,m=1;"), 4096);
+        assert_eq!(data(chunks[1], "\x1b_Gm=1;"), 4096);
+        // 10924 bytes of base64 in all.
+        assert_eq!(data(chunks[2], "\x1b_Gm=0;"), 2732);
+    }
+
+    #[test]
+    fn sixel() {
+        // Seven pixels high, so two bands. The transparent column is left out.
+        let mut out = Vec::new();
+        write_sixel(&mut out, &placement(2, 1, &[RED, CLEAR]), 2, 7);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1bP0;1;0q\"1;1;2;7#180;2;100;0;0#180~-#180@\x1b\\",
+        );
+    }
+
+    #[test]
+    fn sixel_colors_and_runs() {
+        let mut out = Vec::new();
+        write_sixel(&mut out, &placement(5, 1, &[BLUE, BLUE, BLUE, BLUE, GREEN]), 5, 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1bP0;1;0q\"1;1;5;1#5;2;0;0;100#30;2;0;100;0#5!4@$#30!4?@\x1b\\",
+        );
+    }
+
+    #[test]
+    fn iterm2() {
+        let mut out = Vec::new();
+        write_iterm2(&mut out, &placement(1, 1, &[RED]), 1, 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b]1337;File=inline=1;size=73;width=1;height=1;preserveAspectRatio=0:\
+             iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJ\
+             AAAAEElEQVR4AQEFAPr/AP8AAP8FAAH/+lyI0QAAAABJRU5ErkJggg==\x07",
+        );
+    }
+
+    #[test]
+    fn png() {
+        assert_eq!(encode_png(&RgbaImage::new(1, 1, RED.to_vec())), RED_PNG);
+
+        // Rows of 513 bytes, which need two stored blocks between them.
+        let png = encode_png(&RgbaImage::new(128, 128, [RED; 128 * 128].concat()));
+        let idat = &png[(8 + 25)..(png.len() - 12)];
+        assert_eq!(&idat[..8], &[0, 1, 0, 0x90, b'I', b'D', b'A', b'T'][..]);
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 0, 0xff, 0xff, 0, 0][..]);
+        assert_eq!(&idat[(15 + 0xffff)..(20 + 0xffff)], &[1, 0x81, 0, 0x7e, 0xff][..]);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(b"123456789"), 0x091e_01de);
+    }
+}
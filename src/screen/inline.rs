@@ -0,0 +1,178 @@
+//! Rendering inline, below the terminal's existing output.
+//!
+//! Rather than taking over the alternate screen, an inline UI occupies a fixed number of rows
+//! starting from wherever the cursor was when it started. We don't know which rows of the screen
+//! those are, and the terminal may scroll them around underneath us, so the cursor is only ever
+//! moved relative to where it already is.
+
+use super::*;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The distance between tab stops, as most terminals set them.
+const TAB_WIDTH: usize = 8;
+
+impl Buffers {
+    /// Make room for the viewport below the cursor, which must be at the start of a row, scrolling
+    /// the terminal if there isn't enough space. Leaves the cursor at the top-left of the now blank
+    /// viewport.
+    pub(super) fn reserve_viewport(&mut self) {
+        let w = self.front_buffer.width();
+        let h = self.front_buffer.height();
+        for _ in 1..h {
+            self.writing.push(b'\n');
+        }
+        if h > 1 {
+            write!(&mut self.writing, "\x1b[{}A", h - 1).unwrap();
+        }
+        self.set_style(Style::default());
+        write!(&mut self.writing, "\x1b[J").unwrap();
+        self.forget_images();
+        self.front_buffer = Surface::blank(w, h);
+        self.damaged = false;
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    /// Print lines of text above the viewport, where they'll end up in the terminal's scrollback,
+    /// then redraw the viewport below them.
+    pub(super) fn print_above(&mut self, text: &str) {
+        self.set_style(Style::default());
+        self.move_cursor(0, 0);
+        // Lines which are wider than the terminal wrap onto as many rows as they need, so clear
+        // everything below rather than just the rows we expect them to take up.
+        write!(&mut self.writing, "\x1b[J").unwrap();
+        for line in text.lines() {
+            write!(&mut self.writing, "{}\r\n", sanitize_line(line)).unwrap();
+        }
+        self.reserve_viewport();
+    }
+
+    /// Resize the viewport. The terminal may have re-wrapped its contents, so there's no telling
+    /// what state the viewport is in. Clear it and start again.
+    pub(super) fn resize_viewport(&mut self, w: u16, h: u16) {
+        self.set_style(Style::default());
+        self.move_cursor(0, 0);
+        self.writing.push(b'\r');
+        self.front_buffer = Surface::blank(w, h);
+        self.back_buffer = Surface::blank(w, h);
+        self.reserve_viewport();
+    }
+
+    /// Move the cursor below the viewport, leaving the last frame on the screen.
+    pub(super) fn leave_viewport(&mut self) {
+        let h = self.front_buffer.height();
+        self.set_style(Style::default());
+        self.move_cursor(0, h.saturating_sub(1));
+        write!(&mut self.writing, "\r\n").unwrap();
+    }
+}
+
+/// Make a line of text safe to print. Tabs are expanded to spaces, and other control characters,
+/// which could move the cursor or start an escape sequence, are dropped the same as they are from
+/// cells.
+fn sanitize_line(line: &str) -> String {
+    let mut sanitized = String::with_capacity(line.len());
+    let mut column = 0;
+    for grapheme in line.graphemes(true) {
+        if grapheme == "\t" {
+            let spaces = TAB_WIDTH - column % TAB_WIDTH;
+            sanitized.push_str(&" ".repeat(spaces));
+            column += spaces;
+            continue;
+        }
+        let grapheme = Grapheme::new(grapheme);
+        let width = grapheme.width();
+        if width == 0 {
+            continue;
+        }
+        sanitized.push_str(&grapheme.for_terminal());
+        column += width;
+    }
+    sanitized
+}
+
+/// The size of the area to render to, given the size of the terminal.
+pub(super) fn viewport_size(inline_height_opt: Option<u16>, w: u16, h: u16) -> (u16, u16) {
+    match inline_height_opt {
+        Some(inline_height) => (w, cmp::min(inline_height, h)),
+        None => (w, h),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn inline_buffers(w: u16, h: u16) -> Buffers {
+        let mut buffers = Buffers::blank(w, h);
+        buffers.inline = true;
+        buffers
+    }
+
+    #[test]
+    fn reserve() {
+        let mut buffers = inline_buffers(10, 3);
+        buffers.reserve_viewport();
+        assert_eq!(buffers.writing, b"\n\n\x1b[2A\x1b[J");
+
+        // A single row doesn't need moving back up to.
+        let mut buffers = inline_buffers(10, 1);
+        buffers.reserve_viewport();
+        assert_eq!(buffers.writing, b"\x1b[J");
+    }
+
+    #[test]
+    fn print_lines_above() {
+        let mut buffers = inline_buffers(10, 2);
+        buffers.front_buffer.print("old", 0, 0, Style::default());
+        buffers.cursor_x = 3;
+        buffers.cursor_y = 1;
+        buffers.print_above("one\ntwo\n");
+        // The cursor is only ever moved relative to where it is.
+        assert_eq!(buffers.writing, b"\x1bM\r\x1b[Jone\r\ntwo\r\n\n\x1b[1A\x1b[J".to_vec());
+        assert_eq!((buffers.cursor_x, buffers.cursor_y), (0, 0));
+        assert_eq!(buffers.front_buffer.cell(0, 0).c, ' ');
+    }
+
+    #[test]
+    fn print_controls_above() {
+        let mut buffers = inline_buffers(40, 1);
+        buffers.print_above("a\x1b[31mb\rc\u{9b}2J\x07\td\u{7f}\r\nx\ty");
+        let expected = "\x1b[Ja[31mbc2J       d\r\nx       y\r\n\x1b[J";
+        assert_eq!(String::from_utf8_lossy(&buffers.writing), expected);
+    }
+
+    #[test]
+    fn print_wide_lines_above() {
+        let mut buffers = inline_buffers(4, 2);
+        // The terminal wraps this onto three rows, all of which have already been cleared.
+        buffers.print_above("abcdefghij\n\u{4e2d}\u{6587}\u{5b57}");
+        let expected = "\x1b[Jabcdefghij\r\n\u{4e2d}\u{6587}\u{5b57}\r\n\n\x1b[1A\x1b[J";
+        assert_eq!(String::from_utf8_lossy(&buffers.writing), expected);
+    }
+
+    #[test]
+    fn resize() {
+        let mut buffers = inline_buffers(10, 3);
+        buffers.cursor_x = 4;
+        buffers.cursor_y = 2;
+        buffers.resize_viewport(8, 2);
+        assert_eq!(buffers.writing, b"\x1b[2A\r\r\n\x1b[1A\x1b[J".to_vec());
+        assert_eq!(buffers.front_buffer.width(), 8);
+        assert_eq!(buffers.back_buffer.height(), 2);
+    }
+
+    #[test]
+    fn leave() {
+        let mut buffers = inline_buffers(10, 3);
+        buffers.leave_viewport();
+        assert_eq!(buffers.writing, b"\n\n\r\n");
+
+        let mut buffers = inline_buffers(10, 3);
+        buffers.cursor_x = 4;
+        buffers.cursor_y = 2;
+        buffers.leave_viewport();
+        assert_eq!(buffers.writing, b"\r\r\n");
+    }
+}
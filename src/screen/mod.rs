@@ -0,0 +1,1108 @@
+use super::*;
+
+use crate::backend::Backend;
+use crate::graphics::{
+    Cell, Color, ColorDepth, CursorKind, CursorStyle, Grapheme, ImageProtocol, Style, Surface,
+    UnderlineKind,
+};
+use crate::run::RunOptions;
+use crate::widget::Widget;
+
+use futures::channel::{mpsc, oneshot};
+use std::path::PathBuf;
+
+mod capabilities;
+mod cursor;
+mod image;
+mod inline;
+mod recorder;
+mod scroll;
+mod sgr;
+mod title;
+
+pub use self::capabilities::Capabilities;
+pub(crate) use self::recorder::{RecordInput, Recorder, SharedRecorder};
+pub(crate) use self::sgr::{link_transition, sgr_transition};
+
+/// Sequences which tell the terminal to hold off on painting until the whole frame has arrived
+/// (DEC private mode 2026).
+const BEGIN_SYNCHRONIZED_UPDATE: &[u8] = b"\x1b[?2026h";
+const END_SYNCHRONIZED_UPDATE: &[u8] = b"\x1b[?2026l";
+
+const HIDE_CURSOR: &[u8] = b"\x1b[?25l";
+const SHOW_CURSOR: &[u8] = b"\x1b[?25h";
+
+/// How long to wait for the size to settle after a resize before redrawing. Dragging a window edge
+/// produces a burst of SIGWINCHs and there's no point drawing a frame for each of them.
+const RESIZE_SETTLE_TIME: Duration = Duration::from_millis(20);
+
+task_local! {
+    static SCREEN_SIZE: std::cell::Cell<(u16, u16)>; // = std::cell::Cell::new((0, 0));
+    static SCREEN_COMMANDS: mpsc::UnboundedSender<ScreenCommand>;
+}
+
+/// Requests sent to the screen from inside a widget.
+pub(crate) enum ScreenCommand {
+    PrintAbove(String),
+    SetTitle(String),
+    SetIconName(String),
+    ReportDirectory(PathBuf),
+    Redraw,
+    Suspend(oneshot::Sender<io::Result<()>>),
+    /// Run a function while another program uses the terminal. It's passed the error instead if
+    /// the terminal can't be handed over.
+    Release(Box<dyn FnOnce(io::Result<()>) + Send>),
+    Capabilities(oneshot::Sender<Capabilities>),
+    /// A sequence which doesn't change what's on the screen, eg. a query. It's written between
+    /// frames so that it can't end up in the middle of another sequence.
+    Write(Vec<u8>),
+}
+
+pub async fn with_screen<B, F, U>(
+    backend: B,
+    options: &RunOptions,
+    recorder_opt: Option<SharedRecorder>,
+    func: F,
+) -> io::Result<U::Output>
+where
+    B: Backend,
+    F: FnOnce(Screen<B>) -> U,
+    U: Future,
+{
+    let (w, h) = backend.size()?;
+    let (w, h) = inline::viewport_size(options.inline_height, w, h);
+    let (commands_sender, commands) = mpsc::unbounded();
+    let screen = Screen::new(backend, w, h, commands, recorder_opt, options)?;
+    let future = with_screen_size_scope(w, h, func(screen));
+    Ok(SCREEN_COMMANDS.scope(commands_sender, future).await)
+}
+
+/// Run a future with `screen_size()` reporting the given size.
+pub(crate) async fn with_screen_size_scope<F: Future>(w: u16, h: u16, future: F) -> F::Output {
+    SCREEN_SIZE.scope(std::cell::Cell::new((w, h)), future).await
+}
+
+struct Buffers {
+    front_buffer: Surface,
+    back_buffer: Surface,
+    writing: Vec<u8>,
+    amount_written: usize,
+    damaged: bool,
+    cursor_x: u16,
+    cursor_y: u16,
+    current_style: Style,
+    synchronized_output: bool,
+    color_depth: ColorDepth,
+    inline: bool,
+    cursor_visible: bool,
+    cursor_style_opt: Option<CursorStyle>,
+    title_pushed: bool,
+    title_opt: Option<String>,
+    icon_name_opt: Option<String>,
+    image_protocol: ImageProtocol,
+    /// The size of a cell in pixels.
+    cell_size: (u32, u32),
+    /// The kitty image IDs of the front buffer's images, for those that kitty is showing.
+    kitty_ids: Vec<Option<u32>>,
+    next_kitty_id: u32,
+}
+
+impl Buffers {
+    /// Resize the buffers, keeping what's already on the screen where the old and new sizes
+    /// overlap. Only the areas which have been newly exposed get cleared.
+    fn resize(&mut self, w: u16, h: u16) {
+        if self.inline {
+            self.resize_viewport(w, h);
+            return;
+        }
+        let old_w = self.front_buffer.width();
+        let old_h = self.front_buffer.height();
+        if !self.front_buffer.images().is_empty() {
+            // The terminal may have moved images around or cut them up. Start again.
+            self.forget_images();
+            self.damaged = true;
+        }
+        self.front_buffer = self.front_buffer.resized(w, h);
+        self.back_buffer = Surface::blank(w, h);
+        self.writing.reserve(w as usize * h as usize * 2);
+        if self.damaged {
+            // Everything is getting redrawn anyway.
+            return;
+        }
+
+        // The terminal may have moved the cursor, so we can't move it relative to where we think
+        // it is until we've moved it somewhere absolute.
+        let mut cursor_known = false;
+        let mut erase_line_from = |buffers: &mut Buffers, x: u16, y: u16| {
+            buffers.set_style(Style::default());
+            cursor::write_goto(&mut buffers.writing, x, y);
+            write!(&mut buffers.writing, "\x1b[K").unwrap();
+            buffers.cursor_x = x;
+            buffers.cursor_y = y;
+            cursor_known = true;
+        };
+        for y in 0..cmp::min(h, old_h) {
+            if w > old_w {
+                erase_line_from(self, old_w, y);
+            } else if w > 0 && w < old_w && self.front_buffer.cell(w - 1, y).c.width() == 2 {
+                // A wide character has been cut in half.
+                *self.front_buffer.cell_mut(w - 1, y) = Cell::blank();
+                erase_line_from(self, w - 1, y);
+            }
+        }
+        if h > old_h {
+            self.set_style(Style::default());
+            cursor::write_goto(&mut self.writing, 0, old_h);
+            write!(&mut self.writing, "\x1b[J").unwrap();
+            self.cursor_x = 0;
+            self.cursor_y = old_h;
+            cursor_known = true;
+        }
+        if !cursor_known {
+            cursor::write_goto(&mut self.writing, 0, 0);
+            self.cursor_x = 0;
+            self.cursor_y = 0;
+        }
+    }
+
+    /// Redraw everything on the next frame, in case something else has drawn over the screen. It
+    /// may also have moved the cursor and changed its style.
+    fn redraw(&mut self) {
+        self.writing.extend_from_slice(b"\x1b[m");
+        self.current_style = Style::default();
+        self.cursor_visible = true;
+        self.cursor_style_opt = None;
+        if !self.inline {
+            cursor::write_goto(&mut self.writing, 0, 0);
+            self.cursor_x = 0;
+            self.cursor_y = 0;
+        }
+        self.damaged = true;
+    }
+
+    /// Undo everything we've done to the terminal, other than the modes that the backend takes
+    /// care of. Returns the bytes which are still waiting to be written, followed by the ones
+    /// which do this. They need to be written before the backend leaves its modes.
+    fn release_terminal(&mut self) -> Vec<u8> {
+        if self.inline {
+            self.leave_viewport();
+        } else {
+            self.forget_images();
+        }
+        self.set_style(Style::default());
+        let mut finish = self.writing.split_off(self.amount_written);
+        if self.cursor_style_opt.take().is_some() {
+            // Put back the terminal's default cursor style.
+            finish.extend_from_slice(b"\x1b[0 q");
+        }
+        finish.extend_from_slice(SHOW_CURSOR);
+        self.cursor_visible = true;
+        if self.title_pushed {
+            finish.extend_from_slice(title::POP_TITLE);
+            self.title_pushed = false;
+        }
+        finish
+    }
+
+    /// Take the terminal back after `release_terminal`, once the backend has re-entered its
+    /// modes. Anything could have happened to it in the meantime, so everything gets redrawn.
+    fn retake_terminal(&mut self) {
+        self.writing.extend_from_slice(HIDE_CURSOR);
+        self.cursor_visible = false;
+        self.writing.extend_from_slice(b"\x1b[m");
+        self.current_style = Style::default();
+        if self.inline {
+            // Start a fresh viewport below whatever the shell printed.
+            self.writing.push(b'\r');
+            self.reserve_viewport();
+        } else {
+            cursor::write_goto(&mut self.writing, 0, 0);
+            self.cursor_x = 0;
+            self.cursor_y = 0;
+            self.damaged = true;
+        }
+        self.restore_title();
+    }
+
+    fn draw_widget<W>(&mut self, widget: &W)
+    where
+        W: Widget
+    {
+        // Invalidations only last for the frame they're made in.
+        self.back_buffer.take_invalid();
+        let mut surface = self.back_buffer.as_mut();
+        surface.clear();
+        widget.draw(&mut surface);
+    }
+
+    fn swap_buffers(&mut self) {
+        debug_assert_eq!(self.front_buffer.width(), self.back_buffer.width());
+        debug_assert_eq!(self.front_buffer.height(), self.back_buffer.height());
+
+        let frame_start = self.writing.len();
+        if self.synchronized_output {
+            self.writing.extend_from_slice(BEGIN_SYNCHRONIZED_UPDATE);
+        }
+
+        let changes_start = self.writing.len();
+        // Scroll regions are set in absolute rows, which inline mode doesn't know. Images don't
+        // necessarily scroll along with the text.
+        if !self.damaged && !self.inline && !self.has_images() {
+            self.scroll_shifted_rows();
+        }
+        let invalid = self.back_buffer.take_invalid();
+        let image_plan = self.plan_images(&invalid);
+
+        let w = self.front_buffer.width();
+        let h = self.front_buffer.height();
+        let mut x = 0;
+        let mut y = 0;
+        while y < h {
+            let c = self.back_buffer.cell(x, y).c;
+            let char_width = c.width() as u16;
+            let update = image_plan.cell_update(x, y);
+
+            if update == image::CellUpdate::Covered {
+                *self.front_buffer.cell_mut(x, y) = *self.back_buffer.cell(x, y);
+            } else if (
+                self.damaged ||
+                update == image::CellUpdate::Redraw ||
+                self.back_buffer.cell(x, y) != self.front_buffer.cell(x, y) ||
+                invalid.iter().any(|rect| rect.contains(x as i16, y as i16))
+            ) && char_width != 0
+            {
+                self.move_cursor(x, y);
+
+                let style = self.back_buffer.cell(x, y).style;
+                self.set_style(style);
+
+                self.writing.extend_from_slice(c.for_terminal().as_bytes());
+                *self.front_buffer.cell_mut(x, y) = *self.back_buffer.cell(x, y);
+                for i in 1..char_width {
+                    if x + i == w {
+                        break;
+                    }
+                    self.front_buffer.cell_mut(x + i, y).c = Grapheme::from('\0');
+                }
+                // If this took us past the last column then cursor_x is left equal to w, meaning the
+                // terminal's cursor is waiting to wrap. See move_cursor.
+                self.cursor_x = cmp::min(self.cursor_x + char_width, w);
+            }
+            x += 1;
+            if x == w {
+                x = 0;
+                y += 1;
+            }
+        }
+        self.draw_images(image_plan);
+        self.damaged = false;
+
+        if self.cursor_visible && self.writing.len() > changes_start {
+            // Don't let the cursor be seen darting around the screen while we draw.
+            self.writing.splice(changes_start..changes_start, HIDE_CURSOR.iter().cloned());
+            self.cursor_visible = false;
+        }
+        self.place_cursor();
+
+        if self.synchronized_output {
+            if self.writing.len() == frame_start + BEGIN_SYNCHRONIZED_UPDATE.len() {
+                // Nothing changed.
+                self.writing.truncate(frame_start);
+            } else {
+                self.writing.extend_from_slice(END_SYNCHRONIZED_UPDATE);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Buffers {
+    /// Buffers for a blank screen which is already up to date, with the cursor in the top-left
+    /// corner and nothing left to write.
+    fn blank(w: u16, h: u16) -> Buffers {
+        Buffers {
+            front_buffer: Surface::blank(w, h),
+            back_buffer: Surface::blank(w, h),
+            writing: Vec::new(),
+            amount_written: 0,
+            damaged: false,
+            cursor_x: 0,
+            cursor_y: 0,
+            current_style: Style::default(),
+            synchronized_output: false,
+            color_depth: ColorDepth::TrueColor,
+            inline: false,
+            cursor_visible: false,
+            cursor_style_opt: None,
+            title_pushed: false,
+            title_opt: None,
+            icon_name_opt: None,
+            image_protocol: ImageProtocol::HalfBlocks,
+            cell_size: image::DEFAULT_CELL_SIZE,
+            kitty_ids: Vec::new(),
+            next_kitty_id: 1,
+        }
+    }
+}
+
+pub struct Screen<B: Backend> {
+    backend: B,
+    buffers: Buffers,
+    commands: mpsc::UnboundedReceiver<ScreenCommand>,
+    recorder_opt: Option<SharedRecorder>,
+    inline_height_opt: Option<u16>,
+    pending_size_opt: Option<(u16, u16)>,
+    resize_timeout: tokio::time::Sleep,
+    probe: capabilities::Probe,
+    guessed: capabilities::Guessed,
+    released_opt: Option<Released>,
+}
+
+/// Set while another program has the terminal.
+struct Released {
+    /// Completes once the terminal can be taken back.
+    future: Pin<Box<dyn Future<Output = io::Result<()>> + Send>>,
+    /// Where to send the future's result once the terminal has been taken back.
+    sender_opt: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+impl<B: Backend> Screen<B> {
+    pub fn new(
+        mut backend: B,
+        w: u16,
+        h: u16,
+        commands: mpsc::UnboundedReceiver<ScreenCommand>,
+        recorder_opt: Option<SharedRecorder>,
+        options: &RunOptions,
+    ) -> io::Result<Screen<B>> {
+        let inline = options.inline_height.is_some();
+        let guessed = capabilities::Guessed {
+            color_depth: options.color_depth.is_none(),
+            image_protocol: {
+                options.image_protocol.is_none() && backend.env_var("TERMCANDY_IMAGES").is_none()
+            },
+            synchronized_output: backend.env_var("TERMCANDY_SYNC_OUTPUT").is_none(),
+        };
+        // The Linux console prints the parts of queries it doesn't understand.
+        let only_da1 = backend.env_var("TERM").map(|term| term == "linux").unwrap_or(false);
+        backend.enter_modes(inline)?;
+        let writing = HIDE_CURSOR.to_vec();
+        let mut buffers = Buffers {
+            front_buffer: Surface::blank(w, h),
+            back_buffer: Surface::blank(w, h),
+            writing: writing,
+            damaged: true,
+            amount_written: 0,
+            cursor_x: 0,
+            cursor_y: 0,
+            current_style: Style::default(),
+            synchronized_output: detect_synchronized_output(&backend),
+            color_depth: options.color_depth.unwrap_or_else(|| detect_color_depth(&backend)),
+            inline,
+            cursor_visible: false,
+            cursor_style_opt: None,
+            title_pushed: false,
+            title_opt: None,
+            icon_name_opt: None,
+            image_protocol: {
+                options.image_protocol.unwrap_or_else(|| image::detect_image_protocol(&backend))
+            },
+            cell_size: image::DEFAULT_CELL_SIZE,
+            kitty_ids: Vec::new(),
+            next_kitty_id: 1,
+        };
+        buffers.cell_size = cell_size(&backend);
+        if inline {
+            buffers.writing.push(b'\r');
+            buffers.reserve_viewport();
+        }
+        Ok(Screen {
+            backend,
+            buffers,
+            commands,
+            recorder_opt,
+            inline_height_opt: options.inline_height,
+            pending_size_opt: None,
+            resize_timeout: tokio::time::sleep(RESIZE_SETTLE_TIME),
+            probe: {
+                capabilities::Probe::Running(Box::pin(capabilities::probe(only_da1)), Vec::new())
+            },
+            guessed,
+            released_opt: None,
+        })
+    }
+
+    /// Poll for changes to the terminal's size. A burst of resizes is coalesced into one, which
+    /// is applied once the size has stopped changing for `RESIZE_SETTLE_TIME`.
+    pub fn poll_for_resizes(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<(u16, u16)>> {
+        let this = self.get_mut();
+        loop {
+            match this.backend.poll_resize(cx) {
+                Poll::Ready(Ok(size)) => {
+                    this.pending_size_opt = Some(size);
+                    let deadline = tokio::time::Instant::now() + RESIZE_SETTLE_TIME;
+                    this.resize_timeout.reset(deadline);
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+        if this.pending_size_opt.is_none() {
+            return Poll::Pending;
+        }
+        match Pin::new(&mut this.resize_timeout).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(()) => {
+                let (w, h) = this.pending_size_opt.take().unwrap();
+                if let Some(recorder) = this.recorder_opt.as_ref() {
+                    recorder.lock().unwrap().resize(w, h);
+                }
+                let (w, h) = inline::viewport_size(this.inline_height_opt, w, h);
+                SCREEN_SIZE.with(|screen_size| screen_size.set((w, h)));
+                this.buffers.cell_size = cell_size(&this.backend);
+                this.buffers.resize(w, h);
+                Poll::Ready(Ok((w, h)))
+            },
+        }
+    }
+
+    /// Poll for the terminal's answers to the capability queries sent at startup. This needs to
+    /// happen before `poll_commands` so that the queries get written along with the first frame.
+    pub fn poll_capabilities(self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        let this = self.get_mut();
+        let (future, senders) = match &mut this.probe {
+            capabilities::Probe::Running(future, senders) => (future, senders),
+            capabilities::Probe::Done(..) => return,
+        };
+        let capabilities = match future.as_mut().poll(cx) {
+            Poll::Ready(capabilities) => capabilities,
+            Poll::Pending => return,
+        };
+        trace!("terminal capabilities: {:?}", capabilities);
+        for sender in senders.drain(..) {
+            let _ = sender.send(capabilities.clone());
+        }
+        this.buffers.apply_capabilities(&capabilities, &this.guessed);
+        this.probe = capabilities::Probe::Done(capabilities);
+    }
+
+    /// Carry out any requests that have been sent from inside the widget.
+    pub fn poll_commands(self: Pin<&mut Self>, cx: &mut Context<'_>) -> io::Result<()> {
+        let this = self.get_mut();
+        while let Poll::Ready(Some(command)) = this.commands.poll_next_unpin(cx) {
+            match command {
+                ScreenCommand::PrintAbove(text) => {
+                    if this.buffers.inline {
+                        this.buffers.print_above(&text);
+                    }
+                },
+                ScreenCommand::SetTitle(title) => this.buffers.set_title(&title),
+                ScreenCommand::SetIconName(icon_name) => this.buffers.set_icon_name(&icon_name),
+                ScreenCommand::ReportDirectory(path) => this.buffers.report_directory(&path),
+                ScreenCommand::Redraw => this.buffers.redraw(),
+                ScreenCommand::Suspend(sender) => {
+                    if let Err(err) = this.release()? {
+                        let _ = sender.send(Err(err));
+                        continue;
+                    }
+                    this.released_opt = Some(Released {
+                        future: this.backend.suspend(),
+                        sender_opt: Some(sender),
+                    });
+                    // Anything else has to wait until we have the terminal back.
+                    break;
+                },
+                ScreenCommand::Release(func) => {
+                    if let Err(err) = this.release()? {
+                        func(Err(err));
+                        continue;
+                    }
+                    // This could take as long as the user likes, so it gets a thread of its own.
+                    let task = tokio::task::spawn_blocking(move || func(Ok(())));
+                    this.released_opt = Some(Released {
+                        future: Box::pin(async move {
+                            if let Err(err) = task.await {
+                                panic::resume_unwind(err.into_panic());
+                            }
+                            Ok(())
+                        }),
+                        sender_opt: None,
+                    });
+                    break;
+                },
+                ScreenCommand::Write(bytes) => this.buffers.writing.extend_from_slice(&bytes),
+                ScreenCommand::Capabilities(sender) => match &mut this.probe {
+                    capabilities::Probe::Running(_, senders) => senders.push(sender),
+                    capabilities::Probe::Done(capabilities) => {
+                        let _ = sender.send(capabilities.clone());
+                    },
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Put the terminal back the way we found it and hand it over to the backend's `release`, eg.
+    /// to stop the process or run another program in the terminal. Nothing gets drawn until
+    /// `poll_released` takes the terminal back.
+    ///
+    /// If the backend can't release the terminal then it's taken straight back, and its error is
+    /// returned in the inner result. The outer one is for failing to take the terminal back.
+    fn release(&mut self) -> io::Result<io::Result<()>> {
+        let finish = self.buffers.release_terminal();
+        if let Some(recorder) = self.recorder_opt.as_ref() {
+            recorder.lock().unwrap().output(&finish);
+        }
+        self.backend.write_blocking(&finish)?;
+        self.backend.leave_modes()?;
+        crate::input::pause_input(true);
+        if let Err(err) = self.backend.release() {
+            self.retake_terminal()?;
+            return Ok(Err(err));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Wait for whatever the terminal was released for to finish, then take the terminal back
+    /// and redraw everything. Returns `Ready(Ok(()))` straight away if the terminal hasn't been
+    /// released.
+    pub fn poll_released(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let released = match this.released_opt.as_mut() {
+            Some(released) => released,
+            None => return Poll::Ready(Ok(())),
+        };
+        let res = match released.future.as_mut().poll(cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => return Poll::Pending,
+        };
+        let sender_opt = this.released_opt.take().unwrap().sender_opt;
+        let reclaim_res = this.backend.reclaim();
+        this.retake_terminal()?;
+        reclaim_res?;
+        if let Some(sender) = sender_opt {
+            let _ = sender.send(res);
+        }
+        // Commands which arrived in the meantime were left in the queue.
+        cx.waker().wake_by_ref();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Take the terminal back after `release`.
+    fn retake_terminal(&mut self) -> io::Result<()> {
+        crate::input::pause_input(false);
+        self.backend.enter_modes(self.buffers.inline)?;
+        self.buffers.retake_terminal();
+
+        // SIGWINCH only goes to the foreground process group, so we won't have heard about the
+        // terminal being resized if we were stopped.
+        let size = self.backend.size()?;
+        if size != (self.buffers.front_buffer.width(), self.buffers.front_buffer.height()) {
+            self.pending_size_opt = Some(size);
+            self.resize_timeout.reset(tokio::time::Instant::now());
+        }
+        Ok(())
+    }
+
+    pub fn draw_widget<W>(&mut self, widget: &W)
+    where
+        W: Widget
+    {
+        self.buffers.draw_widget(widget)
+    }
+
+    /// Finish writing whatever is already queued up, without starting a new frame.
+    pub fn flush_pending(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Screen::flush_front(&mut this.backend, cx, &mut this.buffers, &this.recorder_opt)
+    }
+
+    pub fn flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Screen::flush_front(&mut this.backend, cx, &mut this.buffers, &this.recorder_opt) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Ready(Ok(())) => (),
+        };
+        this.buffers.swap_buffers();
+        Screen::flush_front(&mut this.backend, cx, &mut this.buffers, &this.recorder_opt)
+    }
+
+    fn flush_front(
+        backend: &mut B,
+        cx: &mut Context<'_>,
+        buffers: &mut Buffers,
+        recorder_opt: &Option<SharedRecorder>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if buffers.amount_written == buffers.writing.len() {
+                buffers.writing.clear();
+                buffers.amount_written = 0;
+                return Poll::Ready(Ok(()));
+            }
+            trace!("screen: {:?}", &buffers.writing[buffers.amount_written..]);
+            match Pin::new(&mut *backend).poll_write(cx, &buffers.writing[buffers.amount_written..]) {
+                Poll::Ready(Ok(n)) => {
+                    let written = &buffers.writing[buffers.amount_written..][..n];
+                    if let Some(recorder) = recorder_opt.as_ref() {
+                        recorder.lock().unwrap().output(written);
+                    }
+                    buffers.amount_written += n;
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<B: Backend> Drop for Screen<B> {
+    fn drop(&mut self) {
+        if self.released_opt.is_some() {
+            // The terminal's already back the way we found it.
+            let _ = self.backend.reclaim();
+            return;
+        }
+        let finish = self.buffers.release_terminal();
+        if let Some(recorder) = self.recorder_opt.as_ref() {
+            recorder.lock().unwrap().output(&finish);
+        }
+        let _ = self.backend.write_blocking(&finish);
+        let _ = self.backend.leave_modes();
+    }
+}
+
+/// Work out the size of a cell in pixels.
+fn cell_size<B: Backend>(backend: &B) -> (u32, u32) {
+    match (backend.size(), backend.size_pixels()) {
+        (Ok((w, h)), Some((pixels_w, pixels_h))) if w > 0 && h > 0 => {
+            (cmp::max(pixels_w as u32 / w as u32, 1), cmp::max(pixels_h as u32 / h as u32, 1))
+        },
+        _ => image::DEFAULT_CELL_SIZE,
+    }
+}
+
+/// Guess whether the terminal supports synchronized updates, based on the environment.
+///
+/// Terminals which don't support them should ignore the sequences, but not all of them do. This
+/// can be overridden by setting `TERMCANDY_SYNC_OUTPUT` to `1` or `0`.
+fn detect_synchronized_output<B: Backend>(backend: &B) -> bool {
+    if let Some(value) = backend.env_var("TERMCANDY_SYNC_OUTPUT") {
+        return value == "1";
+    }
+    let term_program = backend.env_var("TERM_PROGRAM").unwrap_or_default();
+    if ["WezTerm", "iTerm.app", "ghostty", "contour", "vscode"].contains(&&term_program[..]) {
+        return true;
+    }
+    let term = backend.env_var("TERM").unwrap_or_default();
+    ["xterm-kitty", "foot", "alacritty", "contour", "wezterm", "xterm-ghostty"]
+        .iter()
+        .any(|prefix| term.starts_with(prefix))
+}
+
+/// Work out how many colors the terminal supports from the environment and its terminfo entry.
+fn detect_color_depth<B: Backend>(backend: &B) -> ColorDepth {
+    if let Some(colorterm) = backend.env_var("COLORTERM") {
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return ColorDepth::TrueColor;
+        }
+    }
+    let term = match backend.env_var("TERM") {
+        Some(term) => term,
+        None => return ColorDepth::Colors16,
+    };
+    if term.ends_with("-direct") {
+        return ColorDepth::TrueColor;
+    }
+    match crate::terminal::terminfo_max_colors(&term) {
+        Some(colors) if colors >= 1 << 24 => ColorDepth::TrueColor,
+        Some(colors) if colors >= 256 => ColorDepth::Colors256,
+        Some(..) => ColorDepth::Colors16,
+        None if term.contains("256color") => ColorDepth::Colors256,
+        None => ColorDepth::Colors16,
+    }
+}
+
+pub(crate) fn with_screen_size<F, R>(w: u16, h: u16, func: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let (old_w, old_h) = SCREEN_SIZE.with(|screen_size| {
+        let (old_w, old_h) = screen_size.get();
+        screen_size.set((w, h));
+        (old_w, old_h)
+    });
+    let ret = func();
+    SCREEN_SIZE.with(|screen_size| {
+        screen_size.set((old_w, old_h));
+    });
+    ret
+}
+
+/// Print text above an inline UI, into the terminal's scrollback.
+///
+/// This only has an effect when running with `RunOptions::inline_height` set. Each line of the
+/// text is printed permanently above the area the UI is drawn in, and the UI is redrawn below
+/// it. Outside of inline mode the text is discarded.
+///
+/// Tabs are expanded to spaces. Other control characters, such as the `ESC` which starts an
+/// escape sequence, are dropped so that the text can't mess up the terminal.
+pub fn print_above<S: Into<String>>(text: S) {
+    send_command(ScreenCommand::PrintAbove(text.into()));
+}
+
+/// Find out what the terminal supports, by asking it.
+///
+/// The terminal is asked when the UI starts, and this waits for it to answer, which takes a round
+/// trip to the terminal. Terminals which don't answer are given two seconds. After that the
+/// answer is remembered, so this returns straight away.
+///
+/// The same answers are used to correct any settings which were guessed from the environment,
+/// such as the color depth, when `RunOptions` doesn't set them explicitly.
+///
+/// Outside of `termcandy::run` nothing can be found out, so this returns
+/// `Capabilities::default()`.
+pub async fn capabilities() -> Capabilities {
+    let (sender, receiver) = oneshot::channel();
+    if !send_command(ScreenCommand::Capabilities(sender)) {
+        return Capabilities::default();
+    }
+    receiver.await.unwrap_or_default()
+}
+
+/// Find out whether the terminal has a dark background, eg. to pick a color scheme to match.
+///
+/// This asks the terminal for its background color (see `capabilities`), and returns `None` if it
+/// doesn't say.
+pub async fn is_dark_background() -> Option<bool> {
+    capabilities().await.is_dark_background()
+}
+
+/// Redraw the whole screen on the next frame, rather than just the parts which have changed.
+///
+/// Use this when something other than the UI has drawn on the terminal, eg. a background process
+/// writing to it, so that the UI is left looking garbled. Many programs do this when Ctrl-L is
+/// pressed. To redraw just part of the screen, use `SurfaceMut::invalidate` while drawing.
+pub fn redraw() {
+    send_command(ScreenCommand::Redraw);
+}
+
+/// Suspend the program, the way a shell does when the user presses Ctrl-Z.
+///
+/// The terminal is in raw mode while a UI is running, so Ctrl-Z arrives as a key press rather
+/// than stopping the program. Call this when it's pressed to get the usual behaviour. The
+/// terminal is put back the way it was, the program is stopped, and once it's continued (eg. with
+/// `fg`) the UI is redrawn.
+///
+/// This happens before the next frame is drawn, and the returned future completes once the UI is
+/// back. Returns an error outside of `termcandy::run`, or with a backend which isn't the program's
+/// own terminal.
+pub async fn suspend() -> io::Result<()> {
+    let (sender, receiver) = oneshot::channel();
+    if !send_command(ScreenCommand::Suspend(sender)) {
+        return Err(io::Error::new(io::ErrorKind::Other, "not running inside termcandy::run"));
+    }
+    receiver.await.unwrap_or_else(|_canceled| {
+        Err(io::Error::new(io::ErrorKind::Other, "the UI exited before the program was suspended"))
+    })
+}
+
+/// Give the terminal back to the shell while `func` runs, eg. to run an editor or a pager.
+///
+/// Before `func` is called the terminal is put back the way it was before the UI started: the
+/// alternate screen, mouse reporting and raw mode are all left, stdin and stdout are made
+/// blocking again, and the UI stops reading input. Afterwards the UI takes the terminal back and
+/// redraws itself. Nothing is drawn while `func` runs. It's run on tokio's blocking thread pool,
+/// so it's fine for it to block for as long as it likes.
+///
+/// ```no_run
+/// # async fn edit() -> std::io::Result<()> {
+/// let _status = termcandy::with_terminal_released(|| {
+///     std::process::Command::new("vi").arg("notes.txt").status()
+/// }).await??;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Returns an error outside of `termcandy::run`, if the UI exits before `func` gets to run, or if
+/// the backend can't release the terminal (eg. because it's on the other end of a socket). `func`
+/// isn't called in any of these cases.
+pub async fn with_terminal_released<F, R>(func: F) -> io::Result<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let func = Box::new(move |released: io::Result<()>| {
+        let _ = sender.send(released.map(|()| func()));
+    });
+    if !send_command(ScreenCommand::Release(func)) {
+        return Err(io::Error::new(io::ErrorKind::Other, "not running inside termcandy::run"));
+    }
+    receiver.await.unwrap_or_else(|_canceled| {
+        Err(io::Error::new(io::ErrorKind::Other, "the UI exited before the terminal was released"))
+    })
+}
+
+/// Set the terminal's window title. The previous title is restored when the UI exits.
+///
+/// Like everything else the UI draws, the title is set on the next frame.
+pub fn terminal_title<S: Into<String>>(title: S) {
+    send_command(ScreenCommand::SetTitle(title.into()));
+}
+
+/// Set the terminal's icon name, which some terminals show on tabs or in the taskbar. The
+/// previous icon name is restored when the UI exits.
+pub fn terminal_icon_name<S: Into<String>>(icon_name: S) {
+    send_command(ScreenCommand::SetIconName(icon_name.into()));
+}
+
+/// Tell the terminal what the current working directory is, so that it can open new tabs and
+/// windows there.
+pub fn report_working_directory<P: Into<PathBuf>>(path: P) {
+    send_command(ScreenCommand::ReportDirectory(path.into()));
+}
+
+/// Write an escape sequence to the terminal along with the next frame. Returns `false` if there's
+/// no terminal to write to, eg. because we're running under a `Driver`.
+pub(crate) fn write_sequence(bytes: Vec<u8>) -> bool {
+    send_command(ScreenCommand::Write(bytes))
+}
+
+fn send_command(command: ScreenCommand) -> bool {
+    SCREEN_COMMANDS.try_with(|commands| {
+        commands.unbounded_send(command).is_ok()
+    }).unwrap_or(false)
+}
+
+/// Get the size of the screen as seen by the current widget.
+///
+/// # Note
+///
+/// Nesting widgets inside each other (using `Widget::resize`) will cause the nested widget to only
+/// see the size of the region of the screen that it's been allocated.
+pub fn screen_size() -> (u16, u16) {
+    SCREEN_SIZE.with(|screen_size| {
+        screen_size.get()
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::backend::{MockBackend, MockCall};
+    use crate::graphics::Rect;
+    use crate::widget::FutureExt;
+
+    use futures::future;
+
+    const MIDDLE: Rect = Rect { x0: 1, x1: 3, y0: 0, y1: 1 };
+
+    #[test]
+    fn invalidated_cells_are_redrawn() {
+        // Clearing the surface after invalidating part of it doesn't undo the invalidation.
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.draw_widget(&async {}.draw_as(|surface| {
+            surface.invalidate(MIDDLE);
+            surface.clear();
+        }));
+        buffers.swap_buffers();
+        assert_eq!(String::from_utf8_lossy(&buffers.writing), "   ");
+    }
+
+    #[test]
+    fn invalidation_lasts_one_frame() {
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.draw_widget(&async {}.draw_as(|surface| surface.invalidate(MIDDLE)));
+        buffers.draw_widget(&async {}.draw_as(|_| ()));
+        buffers.swap_buffers();
+        assert_eq!(String::from_utf8_lossy(&buffers.writing), "");
+    }
+
+    #[test]
+    fn invalidate_far_outside_region() {
+        let mut buffers = Buffers::blank(10, 1);
+        buffers.draw_widget(&async {}.draw_as(|surface| {
+            let mut region = surface.region(Rect { x0: 6, x1: 10, y0: 0, y1: 1 });
+            region.invalidate(Rect { x0: 0, x1: i16::MAX, y0: 0, y1: i16::MAX });
+        }));
+        buffers.swap_buffers();
+        assert_eq!(String::from_utf8_lossy(&buffers.writing), "\x1b[6C    ");
+    }
+
+    fn modes_entered_and_left(options: &RunOptions) -> (Vec<MockCall>, Vec<u8>) {
+        let backend = MockBackend::new(4, 3);
+        let state = backend.state();
+        let (_commands_sender, commands) = mpsc::unbounded();
+        let screen = Screen::new(backend, 4, 3, commands, None, options).unwrap();
+        let inline = options.inline_height.is_some();
+        assert_eq!(state.lock().unwrap().calls, [MockCall::EnterModes { inline }]);
+        drop(screen);
+        let state = state.lock().unwrap();
+        (state.calls.clone(), state.output.clone())
+    }
+
+    #[tokio::test]
+    async fn fullscreen_modes() {
+        let (calls, output) = modes_entered_and_left(&RunOptions::default());
+        assert_eq!(
+            calls,
+            [MockCall::EnterModes { inline: false }, MockCall::WriteBlocking, MockCall::LeaveModes],
+        );
+        // Nothing had been drawn, so the cursor gets hidden and shown again and that's it.
+        assert_eq!(String::from_utf8_lossy(&output), "\x1b[?25l\x1b[?25h");
+    }
+
+    #[tokio::test]
+    async fn inline_modes() {
+        let options = RunOptions { inline_height: Some(2), .. RunOptions::default() };
+        let (calls, output) = modes_entered_and_left(&options);
+        assert_eq!(
+            calls,
+            [MockCall::EnterModes { inline: true }, MockCall::WriteBlocking, MockCall::LeaveModes],
+        );
+        // The viewport gets reserved and left behind, even though nothing has been drawn in it.
+        assert!(output.ends_with(b"\x1b[?25h"));
+    }
+
+    #[tokio::test]
+    async fn color_depth_override() {
+        let screen_with_depth = |color_depth| {
+            let (_commands_sender, commands) = mpsc::unbounded();
+            let options = RunOptions { color_depth, .. RunOptions::default() };
+            Screen::new(MockBackend::new(4, 3), 4, 3, commands, None, &options).unwrap()
+        };
+        let true_color = Capabilities { true_color: Some(true), .. Capabilities::default() };
+
+        // With nothing in the environment to go on, the terminal only gets 16 colors until it
+        // says otherwise.
+        let mut screen = screen_with_depth(None);
+        assert_eq!(screen.buffers.color_depth, ColorDepth::Colors16);
+        screen.buffers.apply_capabilities(&true_color, &screen.guessed);
+        assert_eq!(screen.buffers.color_depth, ColorDepth::TrueColor);
+
+        // An explicit depth sticks.
+        let mut screen = screen_with_depth(Some(ColorDepth::Colors256));
+        assert_eq!(screen.buffers.color_depth, ColorDepth::Colors256);
+        screen.buffers.apply_capabilities(&true_color, &screen.guessed);
+        assert_eq!(screen.buffers.color_depth, ColorDepth::Colors256);
+        screen.buffers.writing.clear();
+        screen.draw_widget(&async {}.draw_as(|surface| {
+            let style = Style { fg: Color::rgb(255, 0, 0), .. Style::default() };
+            surface.print("x", 0, 0, style);
+        }));
+        screen.buffers.swap_buffers();
+        let output = String::from_utf8_lossy(&screen.buffers.writing);
+        assert!(output.contains("\x1b[38;5;196mx"), "{:?}", output);
+    }
+
+    #[tokio::test]
+    async fn release_and_reclaim() {
+        let backend = MockBackend::new(4, 1);
+        let state = backend.state();
+        let (commands_sender, commands) = mpsc::unbounded();
+        let options = RunOptions::default();
+        let mut screen = Box::pin(Screen::new(backend, 4, 1, commands, None, &options).unwrap());
+        let widget = async {}.draw_as(|surface| surface.print("ab", 0, 0, Style::default()));
+        screen.draw_widget(&widget);
+        future::poll_fn(|cx| screen.as_mut().flush(cx)).await.unwrap();
+
+        let (calls_sender, calls_receiver) = oneshot::channel();
+        let func_state = state.clone();
+        let func = Box::new(move |released: io::Result<()>| {
+            released.unwrap();
+            let _ = calls_sender.send(func_state.lock().unwrap().calls.clone());
+        });
+        commands_sender.unbounded_send(ScreenCommand::Release(func)).unwrap();
+        future::poll_fn(|cx| {
+            screen.as_mut().poll_commands(cx)?;
+            screen.as_mut().poll_released(cx)
+        }).await.unwrap();
+        assert_eq!(
+            calls_receiver.await.unwrap(),
+            [
+                MockCall::EnterModes { inline: false },
+                MockCall::WriteBlocking,
+                MockCall::LeaveModes,
+                MockCall::Release,
+            ],
+        );
+        assert_eq!(
+            &state.lock().unwrap().calls[4..],
+            [MockCall::Reclaim, MockCall::EnterModes { inline: false }],
+        );
+
+        // Nothing has changed, but everything gets redrawn.
+        state.lock().unwrap().output.clear();
+        screen.draw_widget(&widget);
+        future::poll_fn(|cx| screen.as_mut().flush(cx)).await.unwrap();
+        let output = String::from_utf8_lossy(&state.lock().unwrap().output).into_owned();
+        assert_eq!(output, "\x1b[?25l\x1b[m\x1b[Hab  ");
+    }
+
+    /// Suspend the screen's process and wait for it to be continued.
+    async fn suspend_screen<B: Backend>(
+        screen: &mut Pin<Box<Screen<B>>>,
+        commands_sender: &mpsc::UnboundedSender<ScreenCommand>,
+    ) -> io::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        commands_sender.unbounded_send(ScreenCommand::Suspend(sender)).unwrap();
+        future::poll_fn(|cx| {
+            screen.as_mut().poll_commands(cx)?;
+            screen.as_mut().poll_released(cx)
+        }).await.unwrap();
+        receiver.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_release_skips_suspend() {
+        let backend = MockBackend::new(4, 1);
+        let state = backend.state();
+        state.lock().unwrap().release_fails = true;
+        let (commands_sender, commands) = mpsc::unbounded();
+        let options = RunOptions::default();
+        let mut screen = Box::pin(Screen::new(backend, 4, 1, commands, None, &options).unwrap());
+
+        assert!(suspend_screen(&mut screen, &commands_sender).await.is_err());
+        assert_eq!(
+            state.lock().unwrap().calls,
+            [
+                MockCall::EnterModes { inline: false },
+                MockCall::WriteBlocking,
+                MockCall::LeaveModes,
+                MockCall::Release,
+                MockCall::EnterModes { inline: false },
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn resize_while_suspended() {
+        let backend = MockBackend::new(4, 1);
+        let state = backend.state();
+        state.lock().unwrap().size_after_suspend_opt = Some((6, 2));
+        let (commands_sender, commands) = mpsc::unbounded();
+        let options = RunOptions::default();
+        let mut screen = Box::pin(Screen::new(backend, 4, 1, commands, None, &options).unwrap());
+
+        suspend_screen(&mut screen, &commands_sender).await.unwrap();
+        assert_eq!(
+            &state.lock().unwrap().calls[3..],
+            [
+                MockCall::Release,
+                MockCall::Suspend,
+                MockCall::Reclaim,
+                MockCall::EnterModes { inline: false },
+            ],
+        );
+        // There was no SIGWINCH, but the new size gets noticed anyway.
+        let size = with_screen_size_scope(4, 1, {
+            future::poll_fn(|cx| screen.as_mut().poll_for_resizes(cx))
+        }).await.unwrap();
+        assert_eq!(size, (6, 2));
+        assert_eq!(screen.buffers.front_buffer.width(), 6);
+        assert_eq!(screen.buffers.front_buffer.height(), 2);
+    }
+}
@@ -0,0 +1,315 @@
+//! Recording sessions to asciicast v2 files.
+//!
+//! The recording is made up of everything written to the terminal, along with resizes and
+//! (optionally) the raw input read from the terminal, each with the time it happened. The result
+//! can be played back with `asciinema play` to reproduce exactly what the user saw.
+//!
+//! See https://docs.asciinema.org/manual/asciicast/v2/ for the format.
+
+use super::*;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the path to record to, when `RunOptions::record_path` isn't set.
+pub(crate) const RECORD_VAR: &str = "TERMCANDY_RECORD";
+
+/// Environment variable which, when set to `1`, includes input in recordings made because of
+/// `TERMCANDY_RECORD`.
+pub(crate) const RECORD_INPUT_VAR: &str = "TERMCANDY_RECORD_INPUT";
+
+/// A recorder shared between the screen and the input reader.
+pub(crate) type SharedRecorder = Arc<Mutex<Recorder>>;
+
+pub(crate) struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+    record_input: bool,
+    /// The tail of the output which doesn't make up a complete UTF-8 character yet.
+    partial_output: Vec<u8>,
+    /// The same, for input.
+    partial_input: Vec<u8>,
+}
+
+impl Recorder {
+    /// Start recording to the file at `path`, replacing it if it already exists. `w` and `h` are
+    /// the size of the terminal, and `env` holds the environment variables which describe it.
+    pub fn create(
+        path: &Path,
+        w: u16,
+        h: u16,
+        env: &[(&str, String)],
+        record_input: bool,
+    ) -> io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let mut header = format!(
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"env\": {{",
+            w, h, timestamp,
+        );
+        let mut first = true;
+        for (name, value) in env {
+            if !first {
+                header.push_str(", ");
+            }
+            first = false;
+            push_json_string(&mut header, name);
+            header.push_str(": ");
+            push_json_string(&mut header, value);
+        }
+        header.push_str("}}\n");
+        file.write_all(header.as_bytes())?;
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+            record_input,
+            partial_output: Vec::new(),
+            partial_input: Vec::new(),
+        })
+    }
+
+    /// Start a recording if one has been asked for, either through the options or the
+    /// environment.
+    pub fn from_options<B: Backend>(
+        backend: &B,
+        options: &RunOptions,
+        w: u16,
+        h: u16,
+    ) -> io::Result<Option<SharedRecorder>> {
+        let (path, record_input) = match options.record_path.as_ref() {
+            Some(path) => (path.clone(), options.record_input),
+            None => match env::var_os(RECORD_VAR) {
+                Some(path) => {
+                    let record_input = env::var(RECORD_INPUT_VAR).map(|s| s == "1");
+                    (path.into(), record_input.unwrap_or(false))
+                },
+                None => return Ok(None),
+            },
+        };
+        // The terminal being recorded isn't necessarily ours, eg. when serving over a socket.
+        let env: Vec<(&str, String)> = {
+            ["TERM", "SHELL"]
+                .iter()
+                .filter_map(|&name| Some((name, backend.env_var(name)?)))
+                .collect()
+        };
+        let recorder = Recorder::create(&path, w, h, &env, record_input)?;
+        Ok(Some(Arc::new(Mutex::new(recorder))))
+    }
+
+    /// Record bytes written to the terminal.
+    pub fn output(&mut self, bytes: &[u8]) {
+        let data = take_complete_utf8(&mut self.partial_output, bytes);
+        self.event("o", &data);
+    }
+
+    /// Record bytes read from the terminal, if input is being recorded.
+    pub fn input(&mut self, bytes: &[u8]) {
+        if !self.record_input {
+            return;
+        }
+        let data = take_complete_utf8(&mut self.partial_input, bytes);
+        self.event("i", &data);
+    }
+
+    /// Record the terminal being resized.
+    pub fn resize(&mut self, w: u16, h: u16) {
+        self.event("r", &format!("{}x{}", w, h));
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        let mut line = format!("[{:.6}, \"{}\", ", time, code);
+        push_json_string(&mut line, data);
+        line.push_str("]\n");
+        // A recording is a debugging aid. Failing to write one shouldn't break the UI.
+        let _ = self.file.write_all(line.as_bytes());
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+    }
+}
+
+/// Append `bytes` to `partial` and take as much of the result as makes up complete UTF-8
+/// characters. Invalid sequences are replaced with U+FFFD.
+fn take_complete_utf8(partial: &mut Vec<u8>, bytes: &[u8]) -> String {
+    partial.extend_from_slice(bytes);
+    let mut data = String::new();
+    loop {
+        match std::str::from_utf8(partial) {
+            Ok(s) => {
+                data.push_str(s);
+                partial.clear();
+                return data;
+            },
+            Err(err) => {
+                let valid_up_to = err.valid_up_to();
+                data.push_str(std::str::from_utf8(&partial[..valid_up_to]).unwrap());
+                match err.error_len() {
+                    Some(len) => {
+                        data.push('\u{fffd}');
+                        partial.drain(..(valid_up_to + len));
+                    },
+                    None => {
+                        // The character is cut off. Keep the start of it until the rest arrives.
+                        partial.drain(..valid_up_to);
+                        return data;
+                    },
+                }
+            },
+        }
+    }
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Wraps the terminal's input, recording everything read from it.
+#[pin_project]
+pub(crate) struct RecordInput<I> {
+    #[pin]
+    inner: I,
+    recorder_opt: Option<SharedRecorder>,
+}
+
+impl<I> RecordInput<I> {
+    /// Wrap a reader. If `recorder_opt` is `None` the input is passed through untouched.
+    pub fn new(inner: I, recorder_opt: Option<SharedRecorder>) -> RecordInput<I> {
+        RecordInput { inner, recorder_opt }
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for RecordInput<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled_before = buf.filled().len();
+        let poll = this.inner.poll_read(cx, buf);
+        if let Some(recorder) = this.recorder_opt.as_ref() {
+            if let Poll::Ready(Ok(())) = poll {
+                recorder.lock().unwrap().input(&buf.filled()[filled_before..]);
+            }
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn json_string(s: &str) -> String {
+        let mut out = String::new();
+        push_json_string(&mut out, s);
+        out
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(json_string("C:\\dir"), "\"C:\\\\dir\"");
+        assert_eq!(json_string("a\nb\r\tc"), "\"a\\nb\\r\\tc\"");
+        assert_eq!(json_string("\x1b[m\x00\x07\x7f"), "\"\\u001b[m\\u0000\\u0007\\u007f\"");
+        // JSON strings can hold any other character as it is.
+        assert_eq!(json_string("caf\u{e9} \u{4e2d} \u{1f600}"), "\"caf\u{e9} \u{4e2d} \u{1f600}\"");
+    }
+
+    #[test]
+    fn characters_split_across_writes() {
+        let mut partial = Vec::new();
+        let bytes = "a\u{e9}\u{4e2d}\u{1f600}".as_bytes();
+        // Feed the characters through a byte at a time.
+        let mut data = String::new();
+        for (i, &byte) in bytes.iter().enumerate() {
+            let taken = take_complete_utf8(&mut partial, &[byte]);
+            match i {
+                0 | 2 | 5 | 9 => assert!(!taken.is_empty(), "byte {}", i),
+                _ => assert!(taken.is_empty(), "byte {}", i),
+            }
+            data.push_str(&taken);
+        }
+        assert_eq!(data, "a\u{e9}\u{4e2d}\u{1f600}");
+        assert!(partial.is_empty());
+
+        // A character cut off at the end of a write, and another at the start.
+        assert_eq!(take_complete_utf8(&mut partial, b"x\xe4\xb8"), "x");
+        assert_eq!(take_complete_utf8(&mut partial, b"\xady"), "\u{4e2d}y");
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut partial = Vec::new();
+        assert_eq!(take_complete_utf8(&mut partial, b"a\xffb"), "a\u{fffd}b");
+        // A lead byte which the next write doesn't continue.
+        assert_eq!(take_complete_utf8(&mut partial, b"c\xc3"), "c");
+        assert_eq!(take_complete_utf8(&mut partial, b"d"), "\u{fffd}d");
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn recording() {
+        let dir = env::temp_dir().join(format!("termcandy-recorder-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.cast");
+        let env = [("TERM", String::from("xterm-256color")), ("SHELL", String::from("/bin/sh"))];
+        let mut recorder = Recorder::create(&path, 80, 24, &env, false).unwrap();
+        recorder.output(b"\x1b[Hhi \xc3");
+        recorder.input(b"q");
+        recorder.output(b"\xa9");
+        recorder.resize(100, 30);
+        drop(recorder);
+        let recording = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let lines: Vec<&str> = recording.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", recording);
+        let header = lines[0];
+        let start = "{\"version\": 2, \"width\": 80, \"height\": 24, \"timestamp\": ";
+        let end = ", \"env\": {\"TERM\": \"xterm-256color\", \"SHELL\": \"/bin/sh\"}}";
+        assert!(header.starts_with(start) && header.ends_with(end), "{}", header);
+        let timestamp = &header[start.len()..(header.len() - end.len())];
+        assert!(timestamp.parse::<u64>().unwrap() > 0);
+
+        // Each event is [time, code, data]. Input isn't recorded unless it's asked for.
+        let event = |line: &str| {
+            assert!(line.starts_with('[') && line.ends_with(']'), "{}", line);
+            let line = &line[1..(line.len() - 1)];
+            let comma = line.find(", ").unwrap();
+            assert!(line[..comma].parse::<f64>().unwrap() >= 0.0);
+            line[(comma + 2)..].to_owned()
+        };
+        assert_eq!(event(lines[1]), "\"o\", \"\\u001b[Hhi \"");
+        assert_eq!(event(lines[2]), "\"o\", \"\u{e9}\"");
+        assert_eq!(event(lines[3]), "\"r\", \"100x30\"");
+    }
+}
@@ -0,0 +1,167 @@
+//! Redrawing scrolled content cheaply.
+//!
+//! When a block of rows moves up or down the screen, eg. when a log view gains a line, the
+//! cell-by-cell diff in `swap_buffers` would end up rewriting every row in the block. Instead we
+//! get the terminal to shift the rows itself using a scroll region, then let the diff patch up
+//! whatever is left.
+
+use super::*;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Roughly the number of bytes it takes to set a scroll region, scroll it and reset it again,
+/// including moving the cursor back to where it's needed afterwards.
+const SCROLL_COST: usize = 24;
+
+impl Buffers {
+    /// Look for a block of rows in the back buffer which are a shifted copy of rows in the front
+    /// buffer. If there is one, and scrolling would be cheaper than redrawing it, scroll the
+    /// terminal and the front buffer to match.
+    pub(super) fn scroll_shifted_rows(&mut self) {
+        let w = self.front_buffer.width();
+        let h = self.front_buffer.height();
+        if w == 0 || h < 2 {
+            return;
+        }
+
+        let front_hashes = row_hashes(&self.front_buffer);
+        let back_hashes = row_hashes(&self.back_buffer);
+
+        // The block of rows y0..y1 of the back buffer which matches rows (y0 + amount)..(y1 +
+        // amount) of the front buffer, and the number of rows in it which would otherwise need
+        // redrawing.
+        let mut best_opt = None;
+        let mut best_gain = 0;
+        for distance in 1..(h as i16) {
+            for &amount in &[distance, -distance] {
+                let matches = |y: u16| {
+                    let src = y as i16 + amount;
+                    src >= 0 && src < h as i16 && back_hashes[y as usize] == front_hashes[src as usize]
+                };
+                let mut y = 0;
+                while y < h {
+                    if !matches(y) {
+                        y += 1;
+                        continue;
+                    }
+                    let y0 = y;
+                    let mut gain = 0;
+                    while y < h && matches(y) {
+                        if back_hashes[y as usize] != front_hashes[y as usize] {
+                            gain += 1;
+                        }
+                        y += 1;
+                    }
+                    if gain > best_gain {
+                        best_gain = gain;
+                        best_opt = Some((y0, y, amount));
+                    }
+                }
+            }
+        }
+
+        let (y0, y1, amount) = match best_opt {
+            Some(best) => best,
+            None => return,
+        };
+        if best_gain * (w as usize) <= SCROLL_COST {
+            return;
+        }
+        // Make sure this isn't down to a hash collision.
+        for y in y0..y1 {
+            if self.back_buffer.row(y) != self.front_buffer.row((y as i16 + amount) as u16) {
+                return;
+            }
+        }
+
+        let (top, bottom) = if amount > 0 {
+            (y0, y1 + amount as u16)
+        } else {
+            ((y0 as i16 + amount) as u16, y1)
+        };
+
+        // Most terminals fill the rows scrolled into view with the current background color.
+        self.set_style(Style::default());
+        write!(&mut self.writing, "\x1b[{};{}r", top + 1, bottom).unwrap();
+        if amount > 0 {
+            write!(&mut self.writing, "\x1b[{}S", amount).unwrap();
+        } else {
+            write!(&mut self.writing, "\x1b[{}T", -amount).unwrap();
+        }
+        // Resetting the scroll region also moves the cursor to the top-left corner.
+        write!(&mut self.writing, "\x1b[r").unwrap();
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+
+        self.front_buffer.scroll(top, bottom, amount);
+    }
+}
+
+fn row_hashes(surface: &Surface) -> Vec<u64> {
+    (0..surface.height()).map(|y| {
+        let mut hasher = DefaultHasher::new();
+        surface.row(y).hash(&mut hasher);
+        hasher.finish()
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Buffers whose front buffer has a row of each letter in `front`, and whose back buffer has a
+    /// row of each letter in `back`.
+    fn buffers(w: u16, front: &str, back: &str) -> Buffers {
+        let mut buffers = Buffers::blank(w, front.len() as u16);
+        for (y, c) in front.chars().enumerate() {
+            let row: String = std::iter::repeat(c).take(w as usize).collect();
+            buffers.front_buffer.print(&row, 0, y as i16, Style::default());
+        }
+        for (y, c) in back.chars().enumerate() {
+            let row: String = std::iter::repeat(c).take(w as usize).collect();
+            buffers.back_buffer.print(&row, 0, y as i16, Style::default());
+        }
+        buffers
+    }
+
+    fn assert_front_matches_back(buffers: &Buffers, rows: std::ops::Range<u16>) {
+        for y in rows {
+            assert!(buffers.front_buffer.row(y) == buffers.back_buffer.row(y), "row {} differs", y);
+        }
+    }
+
+    #[test]
+    fn scrolls_up() {
+        let mut buffers = buffers(10, "ABCDEF", "BCDEFG");
+        buffers.scroll_shifted_rows();
+        assert_eq!(buffers.writing, b"\x1b[1;6r\x1b[1S\x1b[r");
+        assert_eq!((buffers.cursor_x, buffers.cursor_y), (0, 0));
+        assert_front_matches_back(&buffers, 0..5);
+    }
+
+    #[test]
+    fn scrolls_down() {
+        let mut buffers = buffers(10, "ABCDEF", "GABCDE");
+        buffers.scroll_shifted_rows();
+        assert_eq!(buffers.writing, b"\x1b[1;6r\x1b[1T\x1b[r");
+        assert_front_matches_back(&buffers, 1..6);
+    }
+
+    #[test]
+    fn scrolls_only_the_shifted_block() {
+        let mut buffers = buffers(10, "ABCDEF", "ACDEXF");
+        buffers.scroll_shifted_rows();
+        assert_eq!(buffers.writing, b"\x1b[2;5r\x1b[1S\x1b[r");
+        assert_front_matches_back(&buffers, 0..4);
+        assert_front_matches_back(&buffers, 5..6);
+    }
+
+    #[test]
+    fn skips_scrolling_when_redrawing_is_cheaper() {
+        let mut buffers = buffers(4, "ABCDEF", "BCDEFG");
+        buffers.scroll_shifted_rows();
+        assert_eq!(buffers.writing, b"");
+        assert!(buffers.front_buffer.row(0) != buffers.back_buffer.row(0));
+    }
+}
@@ -0,0 +1,293 @@
+//! Encoding style changes as SGR ("select graphic rendition") sequences.
+//!
+//! Resetting everything and re-emitting the whole style on every change is simple, but it's a lot
+//! of bytes when, say, only the foreground color changes between two runs of text. Instead we work
+//! out what changed between the current style and the new one and only emit that, falling back to
+//! a reset when that ends up shorter.
+
+use super::*;
+
+use crate::graphics::{Attrs, Link};
+
+/// Which slot of the SGR color parameters a color is being set for.
+#[derive(Clone, Copy)]
+enum ColorSlot {
+    Foreground,
+    Background,
+    Underline,
+}
+
+impl Buffers {
+    /// Write the SGR sequence needed to change the terminal's style from `current_style` to
+    /// `style`.
+    pub(super) fn set_style(&mut self, style: Style) {
+        if self.current_style == style {
+            return;
+        }
+        let current = self.downsample_style(self.current_style);
+        let new = self.downsample_style(style);
+        if current == new {
+            // The styles differ in colors that the terminal shows the same way.
+            self.current_style = style;
+            return;
+        }
+        self.writing.extend_from_slice(sgr_transition(current, new).as_bytes());
+        self.writing.extend_from_slice(link_transition(current.link, new.link).as_bytes());
+        self.current_style = style;
+    }
+
+    /// Convert all the colors in the style to ones the terminal can display.
+    fn downsample_style(&self, style: Style) -> Style {
+        let mut style = style;
+        style.fg = style.fg.downsample(self.color_depth);
+        style.bg = style.bg.downsample(self.color_depth);
+        if let Some(underline) = style.attrs.underline.as_mut() {
+            underline.color = underline.color.downsample(self.color_depth);
+        }
+        style
+    }
+}
+
+/// The shortest SGR sequence which changes the terminal's style from `from` to `to`.
+///
+/// Links aren't set with SGR, so `link` is ignored. See `link_transition`.
+pub(crate) fn sgr_transition(from: Style, to: Style) -> String {
+    let from = Style { link: None, .. from };
+    let to = Style { link: None, .. to };
+    if from == to {
+        return String::new();
+    }
+    let mut transition = Vec::new();
+    push_transition(&mut transition, from, to);
+    let mut reset = vec![String::from("0")];
+    push_transition(&mut reset, Style::default(), to);
+
+    if reset.len() == 1 {
+        // A bare `ESC [ m` is a reset.
+        String::from("\x1b[m")
+    } else {
+        let params = if params_len(&reset) < params_len(&transition) {
+            reset
+        } else {
+            transition
+        };
+        format!("\x1b[{}m", params.join(";"))
+    }
+}
+
+/// The OSC 8 sequence which changes the link that printed text belongs to.
+pub(crate) fn link_transition(from: Option<Link>, to: Option<Link>) -> String {
+    if from == to {
+        return String::new();
+    }
+    let mut out = String::new();
+    if from.is_some() {
+        // Close the old link first, rather than relying on the terminal to end it when the next
+        // one starts.
+        out.push_str("\x1b]8;;\x1b\\");
+    }
+    if let Some(link) = to {
+        // A control character in the URL would end the sequence early.
+        let url: String = link.url().chars().filter(|c| !c.is_control()).collect();
+        out.push_str(&format!("\x1b]8;;{}\x1b\\", url));
+    }
+    out
+}
+
+/// The length of the parameters once they've been joined with semicolons.
+fn params_len(params: &[String]) -> usize {
+    params.iter().map(|param| param.len() + 1).sum()
+}
+
+/// Push the SGR parameters needed to get from style `from` to style `to`.
+fn push_transition(params: &mut Vec<String>, from: Style, to: Style) {
+    if from.fg != to.fg {
+        push_color(params, ColorSlot::Foreground, to.fg);
+    }
+    if from.bg != to.bg {
+        push_color(params, ColorSlot::Background, to.bg);
+    }
+    push_attrs_transition(params, from.attrs, to.attrs);
+}
+
+fn push_attrs_transition(params: &mut Vec<String>, from: Attrs, to: Attrs) {
+    // Bold and faint are both turned off by the same code.
+    if (from.bold && !to.bold) || (from.faint && !to.faint) {
+        params.push(String::from("22"));
+        if to.bold {
+            params.push(String::from("1"));
+        }
+        if to.faint {
+            params.push(String::from("2"));
+        }
+    } else {
+        if to.bold && !from.bold {
+            params.push(String::from("1"));
+        }
+        if to.faint && !from.faint {
+            params.push(String::from("2"));
+        }
+    }
+
+    let flags = [
+        (from.italic, to.italic, "3", "23"),
+        (from.blink, to.blink, "5", "25"),
+        (from.strikethrough, to.strikethrough, "9", "29"),
+        (from.overlined, to.overlined, "53", "55"),
+    ];
+    for &(was_on, is_on, on, off) in &flags {
+        if was_on != is_on {
+            params.push(String::from(if is_on { on } else { off }));
+        }
+    }
+
+    match (from.underline, to.underline) {
+        (Some(from_underline), None) => {
+            params.push(String::from("24"));
+            // The underline color outlives the underline, so it would come back with the next one.
+            if from_underline.color != Color::Default {
+                push_color(params, ColorSlot::Underline, Color::Default);
+            }
+        },
+        (None, None) => (),
+        (from_underline_opt, Some(to_underline)) => {
+            let from_kind_opt = from_underline_opt.map(|underline| underline.kind);
+            if from_kind_opt != Some(to_underline.kind) {
+                let param = match to_underline.kind {
+                    UnderlineKind::Single => "4",
+                    UnderlineKind::Double => "4:2",
+                    UnderlineKind::Wavy => "4:3",
+                };
+                params.push(String::from(param));
+            }
+            let from_color = match from_underline_opt {
+                Some(underline) => underline.color,
+                None => Color::Default,
+            };
+            if from_color != to_underline.color {
+                push_color(params, ColorSlot::Underline, to_underline.color);
+            }
+        },
+    }
+}
+
+fn push_color(params: &mut Vec<String>, slot: ColorSlot, color: Color) {
+    let param = match (slot, color) {
+        (ColorSlot::Foreground, Color::Default) => String::from("39"),
+        (ColorSlot::Background, Color::Default) => String::from("49"),
+        (ColorSlot::Underline, Color::Default) => String::from("59"),
+        (ColorSlot::Foreground, Color::Colors16 { code, bright: false }) => {
+            format!("3{}", code as u32)
+        },
+        (ColorSlot::Foreground, Color::Colors16 { code, bright: true }) => {
+            format!("9{}", code as u32)
+        },
+        (ColorSlot::Background, Color::Colors16 { code, bright: false }) => {
+            format!("4{}", code as u32)
+        },
+        (ColorSlot::Background, Color::Colors16 { code, bright: true }) => {
+            format!("10{}", code as u32)
+        },
+        (ColorSlot::Underline, Color::Colors16 { code, bright }) => {
+            format!("58;5;{}", (code as u32) + if bright { 8 } else { 0 })
+        },
+        (slot, Color::Colors256(x)) => format!("{};5;{}", extended_color_code(slot), x),
+        (slot, Color::Rgb { r, g, b }) => {
+            format!("{};2;{};{};{}", extended_color_code(slot), r, g, b)
+        },
+    };
+    params.push(param);
+}
+
+/// The code which introduces a 256-color or RGB color for the slot.
+fn extended_color_code(slot: ColorSlot) -> u32 {
+    match slot {
+        ColorSlot::Foreground => 38,
+        ColorSlot::Background => 48,
+        ColorSlot::Underline => 58,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::graphics::{SurfaceMut, UnderlineStyle};
+    use crate::widget::FutureExt;
+
+    fn underline(color: Color) -> Style {
+        Style::underline(UnderlineStyle { color, ..UnderlineStyle::single() })
+    }
+
+    #[test]
+    fn changes_only_what_differs() {
+        let from = Style { fg: Color::red(), ..Style::bold() };
+        let to = Style { fg: Color::blue(), ..Style::bold() };
+        assert_eq!(sgr_transition(from, to), "\x1b[34m");
+        assert_eq!(sgr_transition(Style::bold(), Style::faint()), "\x1b[0;2m");
+        assert_eq!(sgr_transition(to, Style::default()), "\x1b[m");
+        assert_eq!(sgr_transition(to, to), "");
+    }
+
+    #[test]
+    fn underline_color_is_reset_with_underline() {
+        let red = underline(Color::Rgb { r: 255, g: 0, b: 0 });
+        assert_eq!(sgr_transition(Style::default(), red), "\x1b[4;58;2;255;0;0m");
+        // Keep the foreground, so that a full reset isn't shorter.
+        let fg = Color::Rgb { r: 1, g: 2, b: 3 };
+        assert_eq!(sgr_transition(Style { fg, ..red }, Style::fg(fg)), "\x1b[24;59m");
+        assert_eq!(sgr_transition(Style::bold(), underline(Color::Default)), "\x1b[0;4m");
+    }
+
+    #[test]
+    fn colors_which_downsample_alike_need_no_change() {
+        let mut buffers = Buffers::blank(10, 1);
+        buffers.color_depth = ColorDepth::Colors16;
+        buffers.current_style = Style { fg: Color::Rgb { r: 205, g: 0, b: 0 }, ..Style::bold() };
+        let style = Style { fg: Color::Rgb { r: 200, g: 10, b: 0 }, ..Style::bold() };
+        buffers.set_style(style);
+        assert_eq!(buffers.writing, b"");
+        assert_eq!(buffers.current_style, style);
+    }
+
+    fn render(draw: impl Fn(&mut SurfaceMut<'_>)) -> String {
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.draw_widget(&async {}.draw_as(|surface| draw(surface)));
+        buffers.swap_buffers();
+        String::from_utf8_lossy(&buffers.writing).into_owned()
+    }
+
+    #[test]
+    fn adjacent_links_are_closed_then_opened() {
+        let a = Style::link(Link::new("https://a.example/"));
+        let b = Style::link(Link::new("https://b.example/"));
+        let output = render(|surface| {
+            surface.print("x", 0, 0, a);
+            surface.print("y", 1, 0, b);
+        });
+        assert_eq!(
+            output,
+            "\x1b]8;;https://a.example/\x1b\\x\x1b]8;;\x1b\\\x1b]8;;https://b.example/\x1b\\y",
+        );
+    }
+
+    #[test]
+    fn style_change_inside_link_keeps_it_open() {
+        let link = Link::new("https://a.example/");
+        let output = render(|surface| {
+            surface.print("x", 0, 0, Style::link(link));
+            surface.print("y", 1, 0, Style { link: Some(link), ..Style::bold() });
+        });
+        assert_eq!(output, "\x1b]8;;https://a.example/\x1b\\x\x1b[1my");
+    }
+
+    #[test]
+    fn link_transitions() {
+        let link = Link::new("https://a.example/\x07\x1b\\");
+        assert_eq!(link_transition(None, Some(link)), "\x1b]8;;https://a.example/\\\x1b\\");
+        assert_eq!(link_transition(Some(link), Some(link)), "");
+        assert_eq!(link_transition(Some(link), None), "\x1b]8;;\x1b\\");
+        assert_eq!(link_transition(None, None), "");
+        assert_eq!(Link::new("https://a.example/\x07\x1b\\"), link);
+    }
+}
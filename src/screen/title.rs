@@ -0,0 +1,142 @@
+//! Setting the terminal's title and telling it the working directory.
+
+use super::*;
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// XTWINOPS sequences which save and restore the window title and icon name.
+pub(super) const PUSH_TITLE: &[u8] = b"\x1b[22;0t";
+pub(super) const POP_TITLE: &[u8] = b"\x1b[23;0t";
+
+impl Buffers {
+    /// Set the window title (OSC 2), saving the old one first so that it can be put back on exit.
+    pub(super) fn set_title(&mut self, title: &str) {
+        self.push_title();
+        self.write_osc(&format!("2;{}", strip_controls(title)));
+        self.title_opt = Some(title.to_owned());
+    }
+
+    /// Set the icon name (OSC 1), which is what some terminals show on tabs and taskbars.
+    pub(super) fn set_icon_name(&mut self, icon_name: &str) {
+        self.push_title();
+        self.write_osc(&format!("1;{}", strip_controls(icon_name)));
+        self.icon_name_opt = Some(icon_name.to_owned());
+    }
+
+    /// Put back the title and icon name we had before the terminal was released.
+    pub(super) fn restore_title(&mut self) {
+        if let Some(title) = self.title_opt.take() {
+            self.set_title(&title);
+        }
+        if let Some(icon_name) = self.icon_name_opt.take() {
+            self.set_icon_name(&icon_name);
+        }
+    }
+
+    /// Tell the terminal what our working directory is (OSC 7), so that it can open new tabs and
+    /// windows in the same place.
+    pub(super) fn report_directory(&mut self, path: &Path) {
+        let mut url = format!("7;file://{}", hostname().unwrap_or_default());
+        for &byte in path.as_os_str().as_bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                    url.push(byte as char);
+                },
+                _ => url.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        self.write_osc(&url);
+    }
+
+    fn push_title(&mut self) {
+        if !self.title_pushed {
+            self.writing.extend_from_slice(PUSH_TITLE);
+            self.title_pushed = true;
+        }
+    }
+
+    fn write_osc(&mut self, body: &str) {
+        write!(&mut self.writing, "\x1b]{}\x1b\\", body).unwrap();
+    }
+}
+
+/// Remove control characters, which would end the sequence early.
+fn strip_controls(s: &str) -> String {
+    s.chars().filter(|c| !c.is_control()).collect()
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if res != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn count(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack.windows(needle.len()).filter(|window| *window == needle).count()
+    }
+
+    #[test]
+    fn title_and_icon_name() {
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.set_title("hi");
+        buffers.set_icon_name("tab");
+        assert_eq!(buffers.writing, b"\x1b[22;0t\x1b]2;hi\x1b\\\x1b]1;tab\x1b\\");
+    }
+
+    #[test]
+    fn controls_are_stripped() {
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.set_title("a\x1b]0;b\x07c\x1b\\d\n");
+        assert_eq!(buffers.writing, b"\x1b[22;0t\x1b]2;a]0;bc\\d\x1b\\");
+    }
+
+    #[test]
+    fn title_is_pushed_once_and_popped_on_exit() {
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.set_title("one");
+        buffers.set_title("two");
+        buffers.set_icon_name("three");
+        assert_eq!(count(&buffers.writing, PUSH_TITLE), 1);
+        let finish = buffers.release_terminal();
+        assert_eq!(count(&finish, POP_TITLE), 1);
+        assert!(finish.ends_with(POP_TITLE));
+
+        // Taking the terminal back sets the title again, which needs another push and pop.
+        assert!(buffers.writing.is_empty());
+        buffers.retake_terminal();
+        assert_eq!(count(&buffers.writing, PUSH_TITLE), 1);
+        assert_eq!(count(&buffers.writing, b"\x1b]2;two\x1b\\"), 1);
+        assert_eq!(count(&buffers.writing, b"\x1b]1;three\x1b\\"), 1);
+        let finish = buffers.release_terminal();
+        assert_eq!(count(&finish, POP_TITLE), 1);
+    }
+
+    #[test]
+    fn nothing_popped_without_title() {
+        let mut buffers = Buffers::blank(4, 1);
+        let finish = buffers.release_terminal();
+        assert_eq!(count(&finish, POP_TITLE), 0);
+    }
+
+    #[test]
+    fn directory_is_percent_encoded() {
+        let mut buffers = Buffers::blank(4, 1);
+        buffers.report_directory(Path::new("/home/me/My Files/caf\u{e9}-~_.%"));
+        let expected = format!(
+            "\x1b]7;file://{}/home/me/My%20Files/caf%C3%A9-~_.%25\x1b\\",
+            hostname().unwrap_or_default(),
+        );
+        assert_eq!(String::from_utf8_lossy(&buffers.writing), expected);
+    }
+}
@@ -0,0 +1,135 @@
+use super::*;
+
+use std::cell::Cell;
+use std::path::Path;
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::server::protocol::{self, TermSize};
+use crate::terminal::{
+    non_blocking_stdio, NonBlockingStdin, NonBlockingStdout, RawMode,
+    ENTER_ALTERNATE_SCREEN_SEQUENCE, EXIT_ALTERNATE_SCREEN_SEQUENCE, EXIT_MOUSE_SEQUENCE,
+};
+
+/// Connect the process's terminal to a server started with `serve`, and use it until the server
+/// ends the session.
+///
+/// This puts the terminal into raw mode and passes everything through: input to the server and
+/// the UI back to the terminal. It's what the `termcandy-client` binary does. If the connection
+/// is lost before the server has put the terminal back the way it was, that's done here instead.
+pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    let sigwinch = signal(SignalKind::window_change())?;
+    let env: Vec<(&str, String)> = {
+        protocol::FORWARDED_ENV_VARS
+            .iter()
+            .filter_map(|&name| Some((name, env::var(name).ok()?)))
+            .collect()
+    };
+    writer.write_all(&protocol::hello_frame(terminal_size()?, &env)).await?;
+
+    let (stdin, stdout) = non_blocking_stdio()?;
+    let mut stdout = RawMode::new(stdout);
+    stdout.enter()?;
+
+    let modes_entered = Cell::new(false);
+    let res = {
+        let (frames_sender, frames) = mpsc::unbounded();
+        let sending = future::try_join3(
+            send_input(stdin, frames_sender.clone()),
+            send_resizes(sigwinch, frames_sender),
+            send_frames(writer, frames),
+        );
+        let receiving = receive_output(reader, &mut stdout, &modes_entered);
+        pin_utils::pin_mut!(sending, receiving);
+        // The session is over once the server hangs up.
+        match future::select(receiving, sending).await {
+            Either::Left((res, _)) => res,
+            Either::Right((res, _)) => res.map(|_| ()),
+        }
+    };
+    if modes_entered.get() {
+        let mut sequence = EXIT_MOUSE_SEQUENCE.to_vec();
+        sequence.extend_from_slice(EXIT_ALTERNATE_SCREEN_SEQUENCE);
+        let _ = AsyncWriteExt::write_all(&mut stdout, &sequence).await;
+    }
+    res
+}
+
+fn terminal_size() -> io::Result<TermSize> {
+    let (w, h) = termion::terminal_size()?;
+    let (pixels_w, pixels_h) = termion::terminal_size_pixels().unwrap_or((0, 0));
+    Ok(TermSize { w, h, pixels_w, pixels_h })
+}
+
+async fn send_input(
+    mut stdin: NonBlockingStdin,
+    frames: mpsc::UnboundedSender<Vec<u8>>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = AsyncReadExt::read(&mut stdin, &mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        let mut frame = Vec::with_capacity(protocol::HEADER_LEN + len);
+        protocol::write_frame(&mut frame, protocol::INPUT, &buf[..len]);
+        let _ = frames.unbounded_send(frame);
+    }
+}
+
+async fn send_resizes(
+    mut sigwinch: Signal,
+    frames: mpsc::UnboundedSender<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(()) = sigwinch.recv().await {
+        let _ = frames.unbounded_send(protocol::resize_frame(terminal_size()?));
+    }
+    Ok(())
+}
+
+async fn send_frames(
+    mut writer: OwnedWriteHalf,
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(frame) = frames.next().await {
+        writer.write_all(&frame).await?;
+    }
+    Ok(())
+}
+
+/// Pass the server's output on to the terminal, keeping track of whether it's switched to the
+/// alternate screen and turned on mouse reporting, which it always does together.
+async fn receive_output(
+    mut reader: OwnedReadHalf,
+    stdout: &mut RawMode<NonBlockingStdout>,
+    modes_entered: &Cell<bool>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    // The end of the last read, in case a sequence is split between reads.
+    let mut tail = Vec::new();
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        tail.extend_from_slice(&buf[..len]);
+        let entered = rfind(&tail, ENTER_ALTERNATE_SCREEN_SEQUENCE);
+        let exited = rfind(&tail, EXIT_ALTERNATE_SCREEN_SEQUENCE);
+        if entered.is_some() || exited.is_some() {
+            modes_entered.set(entered > exited);
+        }
+        let keep = EXIT_ALTERNATE_SCREEN_SEQUENCE.len() - 1;
+        tail.drain(..tail.len().saturating_sub(keep));
+        AsyncWriteExt::write_all(stdout, &buf[..len]).await?;
+    }
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|window| window == needle)
+}
@@ -0,0 +1,84 @@
+//! Serving a UI to many terminals at once over a Unix socket.
+//!
+//! `serve` accepts connections on a socket and runs a separate instance of a widget for each one.
+//! Every connection is its own terminal, with its own size, input and screen. On the other end,
+//! `connect` (or the `termcandy-client` binary) hooks the user's terminal up to the socket.
+//!
+//! ```no_run
+//! # fn dashboard() -> termcandy::widget::Image { unimplemented!() }
+//! # async fn example() -> std::io::Result<()> {
+//! let listener = tokio::net::UnixListener::bind("/tmp/dashboard.sock")?;
+//! termcandy::server::serve(listener, Default::default(), || dashboard()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use super::*;
+
+use futures::future;
+use futures::stream::FuturesUnordered;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::run::{run_with_options, RunOptions};
+use crate::widget::Widget;
+
+mod client;
+mod protocol;
+mod socket_backend;
+
+pub use self::client::connect;
+pub use self::socket_backend::{SocketBackend, SocketInput};
+
+/// How long a client gets to introduce itself after connecting.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept connections on `listener` and run a widget, made by calling `make_widget`, for each one.
+///
+/// All of the sessions run inside the future this returns, so the widgets don't need to be `Send`.
+/// Writes to clients never block, so one which stops reading doesn't hold up the others.
+/// A session ends when its widget completes, whose output is dropped, or when the client hangs
+/// up. Errors in one session don't affect the others. This only returns if accepting a connection
+/// fails.
+///
+/// Every session is run with the same `options`. Leave `record_path` unset, since the sessions
+/// would all record to the same file.
+pub async fn serve<F, W>(
+    listener: UnixListener,
+    options: RunOptions,
+    mut make_widget: F,
+) -> io::Result<!>
+where
+    F: FnMut() -> W,
+    W: Widget,
+{
+    let mut sessions = FuturesUnordered::new();
+    future::poll_fn(|cx| {
+        loop {
+            match listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, _addr))) => {
+                    trace!("accepted a connection");
+                    sessions.push(session(stream, options.clone(), make_widget()));
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+        while let Poll::Ready(Some(res)) = sessions.poll_next_unpin(cx) {
+            if let Err(err) = res {
+                trace!("session ended with an error: {}", err);
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+async fn session<W: Widget>(stream: UnixStream, options: RunOptions, widget: W) -> io::Result<()> {
+    let backend = match tokio::time::timeout(HELLO_TIMEOUT, SocketBackend::new(stream)).await {
+        Ok(res) => res?,
+        Err(..) => {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "client didn't say hello"));
+        },
+    };
+    run_with_options(backend, options, widget).await?;
+    Ok(())
+}
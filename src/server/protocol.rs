@@ -0,0 +1,199 @@
+//! What `connect` and `serve` say to each other over the socket.
+//!
+//! The client sends frames: a kind byte, then the length of the payload as a big-endian `u32`,
+//! then the payload. The first frame is always a `HELLO`. The server sends back the terminal
+//! output as it is, without any framing, and hangs up when the session is over.
+
+use super::*;
+
+use std::collections::HashMap;
+
+/// Bumped whenever the frames change in a way that old clients or servers wouldn't understand.
+pub(super) const PROTOCOL_VERSION: u8 = 1;
+
+/// The client's introduction: the protocol version, the size of its terminal and the environment
+/// variables which describe it.
+pub(super) const HELLO: u8 = 0;
+/// Bytes read from the client's terminal.
+pub(super) const INPUT: u8 = 1;
+/// The client's terminal has changed size.
+pub(super) const RESIZE: u8 = 2;
+
+pub(super) const HEADER_LEN: usize = 5;
+
+/// The longest frame the server will buffer. Input frames are passed on as they arrive, so they
+/// can be any length.
+pub(super) const MAX_CONTROL_FRAME_LEN: usize = 64 * 1024;
+
+/// The environment variables which the client passes on, since they say what its terminal can do.
+pub(super) const FORWARDED_ENV_VARS: &[&str] = &[
+    "TERM",
+    "COLORTERM",
+    "TERM_PROGRAM",
+    "KITTY_WINDOW_ID",
+    "SHELL",
+    "TERMCANDY_SYNC_OUTPUT",
+    "TERMCANDY_IMAGES",
+];
+
+/// The size of a terminal, in cells and in pixels. The pixel size is zero if it isn't known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct TermSize {
+    pub w: u16,
+    pub h: u16,
+    pub pixels_w: u16,
+    pub pixels_h: u16,
+}
+
+impl TermSize {
+    const ENCODED_LEN: usize = 8;
+
+    fn encode(self, out: &mut Vec<u8>) {
+        for value in &[self.w, self.h, self.pixels_w, self.pixels_h] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<TermSize> {
+        if bytes.len() < TermSize::ENCODED_LEN {
+            return None;
+        }
+        let read_u16 = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        Some(TermSize {
+            w: read_u16(0),
+            h: read_u16(2),
+            pixels_w: read_u16(4),
+            pixels_h: read_u16(6),
+        })
+    }
+}
+
+pub(super) fn write_frame(out: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Read a frame header, returning the kind of frame and the length of its payload.
+pub(super) fn read_header(header: &[u8]) -> (u8, usize) {
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    (header[0], len as usize)
+}
+
+pub(super) fn resize_frame(size: TermSize) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(TermSize::ENCODED_LEN);
+    size.encode(&mut payload);
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    write_frame(&mut frame, RESIZE, &payload);
+    frame
+}
+
+/// Encode a `HELLO` frame. The environment variables follow the size as `NAME=value`, each ended
+/// with a NUL.
+pub(super) fn hello_frame(size: TermSize, env: &[(&str, String)]) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION];
+    size.encode(&mut payload);
+    for (name, value) in env {
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(b'=');
+        payload.extend(value.bytes().filter(|&b| b != 0));
+        payload.push(0);
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    write_frame(&mut frame, HELLO, &payload);
+    frame
+}
+
+/// Decode the payload of a `HELLO` frame.
+pub(super) fn decode_hello(payload: &[u8]) -> io::Result<(TermSize, HashMap<String, String>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed hello from client");
+    let (&version, rest) = payload.split_first().ok_or_else(invalid)?;
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "client speaks protocol version {}, but the server speaks version {}",
+                version, PROTOCOL_VERSION,
+            ),
+        ));
+    }
+    let size = TermSize::decode(rest).ok_or_else(invalid)?;
+    let mut env = HashMap::new();
+    let vars = &rest[TermSize::ENCODED_LEN..];
+    for var in vars.split(|&b| b == 0).filter(|var| !var.is_empty()) {
+        let var = String::from_utf8_lossy(var);
+        let mut parts = var.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let value = parts.next().ok_or_else(invalid)?;
+        env.insert(name.to_owned(), value.to_owned());
+    }
+    Ok((size, env))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIZE: TermSize = TermSize { w: 80, h: 24, pixels_w: 640, pixels_h: 384 };
+
+    /// Split a frame into its kind and payload, checking the length in the header.
+    fn split_frame(frame: &[u8]) -> (u8, &[u8]) {
+        let (kind, len) = read_header(&frame[..HEADER_LEN]);
+        assert_eq!(len, frame.len() - HEADER_LEN);
+        (kind, &frame[HEADER_LEN..])
+    }
+
+    #[test]
+    fn frame_header() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, INPUT, b"abc");
+        assert_eq!(frame, b"\x01\x00\x00\x00\x03abc");
+        let mut frame = Vec::new();
+        write_frame(&mut frame, INPUT, &[0; 0x10203]);
+        assert_eq!(read_header(&frame[..HEADER_LEN]), (INPUT, 0x10203));
+    }
+
+    #[test]
+    fn resize_round_trip() {
+        let frame = resize_frame(SIZE);
+        let (kind, payload) = split_frame(&frame);
+        assert_eq!(kind, RESIZE);
+        assert_eq!(TermSize::decode(payload), Some(SIZE));
+        assert_eq!(TermSize::decode(&payload[..TermSize::ENCODED_LEN - 1]), None);
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let env = [
+            ("TERM", String::from("xterm-256color")),
+            ("COLORTERM", String::new()),
+            // NULs would end the value early, so they're dropped.
+            ("TERM_PROGRAM", String::from("odd\0=name")),
+        ];
+        let frame = hello_frame(SIZE, &env);
+        let (kind, payload) = split_frame(&frame);
+        assert_eq!(kind, HELLO);
+        let (size, env) = decode_hello(payload).unwrap();
+        assert_eq!(size, SIZE);
+        assert_eq!(env.len(), 3);
+        assert_eq!(env["TERM"], "xterm-256color");
+        assert_eq!(env["COLORTERM"], "");
+        assert_eq!(env["TERM_PROGRAM"], "odd=name");
+    }
+
+    #[test]
+    fn malformed_hello() {
+        let frame = hello_frame(SIZE, &[("TERM", String::from("xterm"))]);
+        let (_, payload) = split_frame(&frame);
+        for len in 0..=TermSize::ENCODED_LEN {
+            assert!(decode_hello(&payload[..len]).is_err(), "truncated to {} bytes", len);
+        }
+        // Cutting a variable short leaves it without a value.
+        assert!(decode_hello(&payload[..payload.len() - 7]).is_err());
+
+        let mut wrong_version = payload.to_vec();
+        wrong_version[0] = PROTOCOL_VERSION + 1;
+        let err = decode_hello(&wrong_version).unwrap_err();
+        assert!(err.to_string().contains("protocol version"), "{}", err);
+    }
+}
@@ -0,0 +1,478 @@
+use super::*;
+
+use std::collections::HashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::backend::Backend;
+use crate::server::protocol::{self, TermSize};
+use crate::terminal::{
+    ENTER_ALTERNATE_SCREEN_SEQUENCE, ENTER_MOUSE_SEQUENCE, EXIT_ALTERNATE_SCREEN_SEQUENCE,
+    EXIT_MOUSE_SEQUENCE,
+};
+
+/// How long a client gets to take the last of the output once its session is over, in case it's
+/// stopped reading.
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A backend for a terminal on the other end of a Unix socket, connected with `connect`.
+///
+/// The client takes care of raw mode, so entering modes here only switches to the alternate
+/// screen and turns on mouse reporting. There's no process to stop or terminal to hand over, so
+/// `suspend` and `with_terminal_released` return errors.
+///
+/// Since many sessions share a thread, `write_blocking` doesn't actually block. Whatever doesn't
+/// fit in the socket's buffer is sent before the next frame, or once the backend is dropped, by a
+/// task of its own.
+pub struct SocketBackend {
+    /// Only taken when the backend is dropped, to finish sending `unsent`.
+    writer_opt: Option<OwnedWriteHalf>,
+    /// What `write_blocking` had no room to send yet.
+    unsent: Vec<u8>,
+    input_opt: Option<SocketInput>,
+    shared: Arc<Mutex<Shared>>,
+    env: HashMap<String, String>,
+    modes_entered: bool,
+}
+
+/// The state shared between the backend and its input, which is where resizes arrive.
+struct Shared {
+    size: TermSize,
+    resized: bool,
+    disconnected: bool,
+    waker_opt: Option<Waker>,
+}
+
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker_opt.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl SocketBackend {
+    /// Create a backend for a connection accepted from a client. This waits for the client to say
+    /// how big its terminal is.
+    pub async fn new(stream: UnixStream) -> io::Result<SocketBackend> {
+        let (mut reader, writer) = stream.into_split();
+        let mut header = [0u8; protocol::HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+        let (kind, len) = protocol::read_header(&header);
+        if kind != protocol::HELLO || len > protocol::MAX_CONTROL_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "client didn't say hello"));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+        let (size, env) = protocol::decode_hello(&payload)?;
+        let shared = Arc::new(Mutex::new(Shared {
+            size,
+            resized: false,
+            disconnected: false,
+            waker_opt: None,
+        }));
+        let input = SocketInput {
+            reader,
+            buffer: Vec::new(),
+            input_remaining: 0,
+            shared: shared.clone(),
+        };
+        Ok(SocketBackend {
+            writer_opt: Some(writer),
+            unsent: Vec::new(),
+            input_opt: Some(input),
+            shared,
+            env,
+            modes_entered: false,
+        })
+    }
+}
+
+impl Backend for SocketBackend {
+    type Input = SocketInput;
+
+    fn take_input(&mut self) -> io::Result<SocketInput> {
+        match self.input_opt.take() {
+            Some(input) => Ok(input),
+            None => Err(io::Error::new(io::ErrorKind::Other, "input has already been taken")),
+        }
+    }
+
+    fn size(&self) -> io::Result<(u16, u16)> {
+        let size = self.shared.lock().unwrap().size;
+        Ok((size.w, size.h))
+    }
+
+    fn size_pixels(&self) -> Option<(u16, u16)> {
+        let size = self.shared.lock().unwrap().size;
+        Some((size.pixels_w, size.pixels_h)).filter(|&(w, h)| w > 0 && h > 0)
+    }
+
+    fn env_var(&self, name: &str) -> Option<String> {
+        self.env.get(name).cloned()
+    }
+
+    fn poll_resize(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(u16, u16)>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.disconnected {
+            // There's nobody left to draw for. Failing here ends the session.
+            let err = io::Error::new(io::ErrorKind::ConnectionAborted, "client disconnected");
+            return Poll::Ready(Err(err));
+        }
+        if shared.resized {
+            shared.resized = false;
+            return Poll::Ready(Ok((shared.size.w, shared.size.h)));
+        }
+        shared.waker_opt = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn enter_modes(&mut self, inline: bool) -> io::Result<()> {
+        if inline || self.modes_entered {
+            return Ok(());
+        }
+        let mut sequence = ENTER_ALTERNATE_SCREEN_SEQUENCE.to_vec();
+        sequence.extend_from_slice(ENTER_MOUSE_SEQUENCE);
+        self.write_blocking(&sequence)?;
+        self.modes_entered = true;
+        Ok(())
+    }
+
+    fn leave_modes(&mut self) -> io::Result<()> {
+        if !self.modes_entered {
+            return Ok(());
+        }
+        let mut sequence = EXIT_MOUSE_SEQUENCE.to_vec();
+        sequence.extend_from_slice(EXIT_ALTERNATE_SCREEN_SEQUENCE);
+        self.modes_entered = false;
+        self.write_blocking(&sequence)
+    }
+
+    fn write_blocking(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        if self.unsent.is_empty() {
+            let sent = send_nonblocking(self.writer().as_ref().as_raw_fd(), buf)?;
+            buf = &buf[sent..];
+        }
+        self.unsent.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl SocketBackend {
+    fn writer(&mut self) -> &mut OwnedWriteHalf {
+        self.writer_opt.as_mut().unwrap()
+    }
+
+    fn poll_send_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let writer = self.writer_opt.as_mut().unwrap();
+            match Pin::new(writer).poll_write(cx, &self.unsent) {
+                Poll::Ready(Ok(0)) => {
+                    let err = io::Error::new(io::ErrorKind::WriteZero, "client hung up");
+                    return Poll::Ready(Err(err));
+                },
+                Poll::Ready(Ok(len)) => {
+                    self.unsent.drain(..len);
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SocketBackend {
+    fn drop(&mut self) {
+        if self.unsent.is_empty() {
+            return;
+        }
+        let mut writer = match self.writer_opt.take() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let unsent = mem::take(&mut self.unsent);
+        // This is usually the exit sequence, without which the client's terminal is left on the
+        // alternate screen.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let _ = tokio::time::timeout(TEARDOWN_TIMEOUT, writer.write_all(&unsent)).await;
+            });
+        }
+    }
+}
+
+impl AsyncWrite for SocketBackend {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_send_unsent(cx) {
+            Poll::Ready(Ok(())) => Pin::new(this.writer()).poll_write(cx, buf),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_send_unsent(cx) {
+            Poll::Ready(Ok(())) => Pin::new(this.writer()).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_send_unsent(cx) {
+            Poll::Ready(Ok(())) => Pin::new(this.writer()).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// The input half of a `SocketBackend`. This unpacks the client's frames, passing on the input
+/// and handing resizes to the backend.
+pub struct SocketInput {
+    reader: OwnedReadHalf,
+    /// Bytes read from the socket which haven't been dealt with yet.
+    buffer: Vec<u8>,
+    /// How much of the current input frame is still to be passed on.
+    input_remaining: usize,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl SocketInput {
+    fn disconnected(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.disconnected = true;
+        shared.wake();
+    }
+
+    fn handle_control_frame(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        match kind {
+            protocol::RESIZE => {
+                let size = match TermSize::decode(payload) {
+                    Some(size) => size,
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed resize"));
+                    },
+                };
+                let mut shared = self.shared.lock().unwrap();
+                shared.size = size;
+                shared.resized = true;
+                shared.wake();
+            },
+            // Newer clients may send things we don't know about.
+            _ => trace!("ignoring frame of kind {} from client", kind),
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for SocketInput {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        read_buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if read_buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if this.input_remaining > 0 {
+                if !this.buffer.is_empty() {
+                    let len = cmp::min(this.input_remaining, this.buffer.len());
+                    let len = cmp::min(len, read_buf.remaining());
+                    read_buf.put_slice(&this.buffer[..len]);
+                    this.buffer.drain(..len);
+                    this.input_remaining -= len;
+                    return Poll::Ready(Ok(()));
+                }
+            } else if this.buffer.len() >= protocol::HEADER_LEN {
+                let (kind, len) = protocol::read_header(&this.buffer);
+                if kind == protocol::INPUT {
+                    this.buffer.drain(..protocol::HEADER_LEN);
+                    this.input_remaining = len;
+                    continue;
+                }
+                if len > protocol::MAX_CONTROL_FRAME_LEN {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "frame too long");
+                    return Poll::Ready(Err(err));
+                }
+                let frame_len = protocol::HEADER_LEN + len;
+                if this.buffer.len() >= frame_len {
+                    let frame: Vec<u8> = this.buffer.drain(..frame_len).collect();
+                    this.handle_control_frame(kind, &frame[protocol::HEADER_LEN..])?;
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.reader).poll_read(cx, &mut chunk_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => {
+                    this.disconnected();
+                    return Poll::Ready(Err(err));
+                },
+                Poll::Ready(Ok(())) => {
+                    if chunk_buf.filled().is_empty() {
+                        // The client hung up. Pass on the end of the input.
+                        this.disconnected();
+                        return Poll::Ready(Ok(()));
+                    }
+                    this.buffer.extend_from_slice(chunk_buf.filled());
+                },
+            }
+        }
+    }
+}
+
+/// Send as much of `buf` as there's room for in a non-blocking socket, returning how much that
+/// was.
+fn send_nonblocking(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let mut sent = 0;
+    while sent < buf.len() {
+        let res = unsafe {
+            let rest = &buf[sent..];
+            libc::send(fd, rest.as_ptr() as *const _, rest.len(), libc::MSG_NOSIGNAL)
+        };
+        if res >= 0 {
+            sent += res as usize;
+            continue;
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => (),
+            io::ErrorKind::WouldBlock => break,
+            _ => return Err(err),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::future;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn write_blocking_does_not_wait_for_the_client() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let size = TermSize { w: 80, h: 24, pixels_w: 0, pixels_h: 0 };
+        client.write_all(&protocol::hello_frame(size, &[])).await.unwrap();
+        let mut backend = SocketBackend::new(server).await.unwrap();
+
+        // Far more than the socket can buffer, with nobody reading.
+        let output: Vec<u8> = (0..(4 << 20)).map(|i| i as u8).collect();
+        let start = Instant::now();
+        backend.write_blocking(&output).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // What's left is sent once the backend's gone.
+        drop(backend);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received == output);
+    }
+
+    async fn connect_backend(client: &mut UnixStream, server: UnixStream) -> SocketBackend {
+        let size = TermSize { w: 80, h: 24, pixels_w: 0, pixels_h: 0 };
+        client.write_all(&protocol::hello_frame(size, &[])).await.unwrap();
+        SocketBackend::new(server).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut backend = connect_backend(&mut client, server).await;
+        let mut input = backend.take_input().unwrap();
+
+        let mut frames = Vec::new();
+        protocol::write_frame(&mut frames, protocol::INPUT, b"hello");
+        let size = TermSize { w: 100, h: 30, pixels_w: 0, pixels_h: 0 };
+        frames.extend_from_slice(&protocol::resize_frame(size));
+        protocol::write_frame(&mut frames, protocol::INPUT, b" world");
+        // Frames of kinds we don't know are skipped.
+        protocol::write_frame(&mut frames, 99, b"?");
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            input.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        for byte in frames {
+            client.write_all(&[byte]).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let size = future::poll_fn(|cx| backend.poll_resize(cx)).await.unwrap();
+        assert_eq!(size, (100, 30));
+
+        drop(client);
+        assert_eq!(reader.await.unwrap(), b"hello world");
+        assert!(future::poll_fn(|cx| backend.poll_resize(cx)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut frame = Vec::new();
+        protocol::write_frame(&mut frame, protocol::HELLO, &[]);
+        frame[1..5].copy_from_slice(&(protocol::MAX_CONTROL_FRAME_LEN as u32 + 1).to_be_bytes());
+        client.write_all(&frame).await.unwrap();
+        assert!(SocketBackend::new(server).await.is_err());
+
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut backend = connect_backend(&mut client, server).await;
+        let mut input = backend.take_input().unwrap();
+        frame[0] = protocol::RESIZE;
+        client.write_all(&frame).await.unwrap();
+        let err = input.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Input frames are passed on as they arrive, so they can be as long as they like.
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut backend = connect_backend(&mut client, server).await;
+        let mut input = backend.take_input().unwrap();
+        frame[0] = protocol::INPUT;
+        client.write_all(&frame).await.unwrap();
+        client.write_all(b"abc").await.unwrap();
+        let mut received = [0; 16];
+        let len = input.read(&mut received).await.unwrap();
+        assert_eq!(&received[..len], b"abc");
+    }
+
+    #[tokio::test]
+    async fn truncated_frames() {
+        // The client hangs up part way through saying hello.
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let size = TermSize { w: 80, h: 24, pixels_w: 0, pixels_h: 0 };
+        let hello = protocol::hello_frame(size, &[]);
+        client.write_all(&hello[..hello.len() - 1]).await.unwrap();
+        drop(client);
+        let err = SocketBackend::new(server).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Or part way through a resize, which is the end of the input.
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut backend = connect_backend(&mut client, server).await;
+        let mut input = backend.take_input().unwrap();
+        let frame = protocol::resize_frame(TermSize { w: 1, h: 1, pixels_w: 0, pixels_h: 0 });
+        client.write_all(&frame[..frame.len() - 1]).await.unwrap();
+        drop(client);
+        assert_eq!(input.read(&mut [0; 16]).await.unwrap(), 0);
+        assert!(future::poll_fn(|cx| backend.poll_resize(cx)).await.is_err());
+    }
+}
@@ -2,36 +2,61 @@ use super::*;
 
 use crate::terminal::Blocking;
 
-const ENTER_ALTERNATE_SCREEN_SEQUENCE: &'static [u8] = b"\x1b[?1049h";
+pub(crate) const ENTER_ALTERNATE_SCREEN_SEQUENCE: &[u8] = b"\x1b[?1049h";
 
-const EXIT_ALTERNATE_SCREEN_SEQUENCE: &'static [u8] = b"\x1b[?1049l";
+pub(crate) const EXIT_ALTERNATE_SCREEN_SEQUENCE: &[u8] = b"\x1b[?1049l";
 
 #[pin_project]
 pub struct AlternateScreen<W: Write> {
     #[pin]
     inner: W,
     enabled: bool,
+    active: bool,
 }
 
 impl<W: Write + AsyncWrite> AlternateScreen<W> {
-    pub async fn new(mut inner: W) -> io::Result<AlternateScreen<W>> {
+    /// Wrap a writer. The alternate screen isn't switched to until `enter` is called.
+    pub fn new(inner: W) -> AlternateScreen<W> {
         let enabled = env::var("TERMCANDY_NO_ALT_SCREEN").map(|s| s != "1").unwrap_or(true);
-        if enabled {
-            inner.write_all(ENTER_ALTERNATE_SCREEN_SEQUENCE)?;
-        }
-        Ok(AlternateScreen {
+        AlternateScreen {
             inner,
             enabled,
-        })
+            active: false,
+        }
+    }
+
+    /// Switch to the alternate screen, unless it's been disabled with `TERMCANDY_NO_ALT_SCREEN`.
+    pub fn enter(&mut self) -> io::Result<()> {
+        if self.enabled && !self.active {
+            let blocking = Blocking::new()?;
+            Write::write_all(&mut self.inner, ENTER_ALTERNATE_SCREEN_SEQUENCE)?;
+            drop(blocking);
+            self.active = true;
+        }
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
     }
 }
 
-impl<W: Write> Drop for AlternateScreen<W> {
-    fn drop(&mut self) {
-        if let Ok(blocking) = Blocking::new() {
-            let _ = Write::write_all(&mut self.inner, EXIT_ALTERNATE_SCREEN_SEQUENCE);
+impl<W: Write> AlternateScreen<W> {
+    /// Switch back to the main screen.
+    pub fn leave(&mut self) -> io::Result<()> {
+        if self.active {
+            let blocking = Blocking::new()?;
+            Write::write_all(&mut self.inner, EXIT_ALTERNATE_SCREEN_SEQUENCE)?;
             drop(blocking);
+            self.active = false;
         }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for AlternateScreen<W> {
+    fn drop(&mut self) {
+        let _ = self.leave();
     }
 }
 
@@ -4,9 +4,11 @@ mod alternate_screen;
 mod mouse_terminal;
 mod non_blocking;
 mod raw_mode;
+mod terminfo;
 
 pub use alternate_screen::*;
 pub use mouse_terminal::*;
 pub use non_blocking::*;
 pub use raw_mode::*;
+pub use terminfo::*;
 
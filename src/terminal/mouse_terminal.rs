@@ -3,31 +3,56 @@ use super::*;
 use crate::terminal::Blocking;
 
 /// A sequence of escape codes to enable terminal mouse support.
-const ENTER_MOUSE_SEQUENCE: &'static [u8] = b"\x1b[?1000h\x1b[?1002h\x1b[?1015h\x1b[?1006h";
+pub(crate) const ENTER_MOUSE_SEQUENCE: &[u8] = b"\x1b[?1000h\x1b[?1002h\x1b[?1015h\x1b[?1006h";
 
 /// A sequence of escape codes to disable terminal mouse support.
-const EXIT_MOUSE_SEQUENCE: &'static [u8] = b"\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l";
+pub(crate) const EXIT_MOUSE_SEQUENCE: &[u8] = b"\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l";
 
 #[pin_project]
 pub struct MouseTerminal<W: Write> {
     #[pin]
     inner: W,
-    drop_written: bool,
+    enabled: bool,
 }
 
 impl<W: Write + AsyncWrite> MouseTerminal<W> {
-    pub async fn new(mut inner: W) -> io::Result<MouseTerminal<W>> {
-        inner.write_all(ENTER_MOUSE_SEQUENCE)?;
-        Ok(MouseTerminal { inner, drop_written: false })
+    /// Wrap a writer. Mouse support isn't enabled until `enter` is called.
+    pub fn new(inner: W) -> MouseTerminal<W> {
+        MouseTerminal { inner, enabled: false }
+    }
+
+    /// Enable terminal mouse support.
+    pub fn enter(&mut self) -> io::Result<()> {
+        if !self.enabled {
+            let blocking = Blocking::new()?;
+            Write::write_all(&mut self.inner, ENTER_MOUSE_SEQUENCE)?;
+            drop(blocking);
+            self.enabled = true;
+        }
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
     }
 }
 
-impl<W: Write> Drop for MouseTerminal<W> {
-    fn drop(&mut self) {
-        if let Ok(blocking) = Blocking::new() {
-            let _ = Write::write_all(&mut self.inner, EXIT_MOUSE_SEQUENCE);
+impl<W: Write> MouseTerminal<W> {
+    /// Disable terminal mouse support.
+    pub fn leave(&mut self) -> io::Result<()> {
+        if self.enabled {
+            let blocking = Blocking::new()?;
+            Write::write_all(&mut self.inner, EXIT_MOUSE_SEQUENCE)?;
             drop(blocking);
+            self.enabled = false;
         }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for MouseTerminal<W> {
+    fn drop(&mut self) {
+        let _ = self.leave();
     }
 }
 
@@ -4,26 +4,43 @@ use super::*;
 pub struct RawMode<W: Write + AsyncWrite> {
     #[pin]
     inner: W,
-    prev_termios: libc::termios,
+    prev_termios_opt: Option<libc::termios>,
 }
 
 impl<W: Write + AsyncWrite> RawMode<W> {
-    pub fn new(inner: W) -> io::Result<RawMode<W>> {
+    /// Wrap a writer. The terminal isn't put into raw mode until `enter` is called.
+    pub fn new(inner: W) -> RawMode<W> {
+        RawMode {
+            inner: inner,
+            prev_termios_opt: None,
+        }
+    }
+
+    /// Put the terminal into raw mode, remembering the previous terminal attributes.
+    pub fn enter(&mut self) -> io::Result<()> {
+        if self.prev_termios_opt.is_some() {
+            return Ok(());
+        }
         let prev_termios = get_terminal_attr()?;
         let mut termios = prev_termios;
         raw_terminal_attr(&mut termios);
         set_terminal_attr(&termios)?;
+        self.prev_termios_opt = Some(prev_termios);
+        Ok(())
+    }
 
-        Ok(RawMode {
-            inner: inner,
-            prev_termios,
-        })
+    /// Restore the terminal attributes that were in place before `enter` was called.
+    pub fn leave(&mut self) -> io::Result<()> {
+        if let Some(prev_termios) = self.prev_termios_opt.take() {
+            set_terminal_attr(&prev_termios)?;
+        }
+        Ok(())
     }
 }
 
 impl<W: Write + AsyncWrite> Drop for RawMode<W> {
     fn drop(&mut self) {
-        let _ = set_terminal_attr(&self.prev_termios);
+        let _ = self.leave();
     }
 }
 
@@ -0,0 +1,154 @@
+use super::*;
+
+use std::path::PathBuf;
+
+const MAGIC_LEGACY: u16 = 0o432;
+const MAGIC_32_BIT: u16 = 0o1036;
+
+/// Index of the `colors` capability in the terminfo numbers section.
+const COLORS_INDEX: usize = 13;
+
+/// Look up the number of colors that a terminal type supports according to its compiled terminfo
+/// entry. Returns `None` if the entry can't be found or doesn't say.
+pub fn terminfo_max_colors(term: &str) -> Option<u32> {
+    let data = terminfo_dirs().into_iter().find_map(|dir| read_entry(dir, term))?;
+    parse_max_colors(&data)
+}
+
+/// The directories to search for terminfo entries, in the order that ncurses searches them.
+fn terminfo_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = env::var_os("TERMINFO") {
+        dirs.push(PathBuf::from(dir));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        dirs.push(PathBuf::from(home).join(".terminfo"));
+    }
+    if let Ok(terminfo_dirs) = env::var("TERMINFO_DIRS") {
+        for dir in terminfo_dirs.split(':') {
+            if !dir.is_empty() {
+                dirs.push(PathBuf::from(dir));
+            }
+        }
+    }
+    for dir in &["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo", "/usr/lib/terminfo"] {
+        dirs.push(PathBuf::from(dir));
+    }
+    dirs
+}
+
+fn read_entry(dir: PathBuf, term: &str) -> Option<Vec<u8>> {
+    let first = term.chars().next()?;
+    // Entries are filed under their first letter, or its hex code on case-insensitive filesystems.
+    let subdirs = [first.to_string(), format!("{:x}", first as u32)];
+    subdirs.iter().find_map(|subdir| std::fs::read(dir.join(subdir).join(term)).ok())
+}
+
+fn parse_max_colors(data: &[u8]) -> Option<u32> {
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = data.get(offset..(offset + 2))?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let magic = read_u16(0)?;
+    let number_size = match magic {
+        MAGIC_LEGACY => 2,
+        MAGIC_32_BIT => 4,
+        _ => return None,
+    };
+    let names_size = read_u16(2)? as usize;
+    let bools_count = read_u16(4)? as usize;
+    let numbers_count = read_u16(6)? as usize;
+    if COLORS_INDEX >= numbers_count {
+        return None;
+    }
+
+    let mut offset = 12 + names_size + bools_count;
+    // The numbers section is aligned to an even offset.
+    offset += offset % 2;
+    offset += COLORS_INDEX * number_size;
+    let bytes = data.get(offset..(offset + number_size))?;
+    let colors = if number_size == 2 {
+        i16::from_le_bytes([bytes[0], bytes[1]]) as i32
+    } else {
+        i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+    // Negative values mean the capability is absent or cancelled.
+    if colors < 0 {
+        None
+    } else {
+        Some(colors as u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A compiled terminfo entry with the given numbers and no strings.
+    fn entry(magic: u16, numbers: &[i32]) -> Vec<u8> {
+        let names = b"test|Test\0";
+        let bools = [1u8];
+        let mut data = Vec::new();
+        let header = [magic, names.len() as u16, bools.len() as u16, numbers.len() as u16, 0, 0];
+        for value in &header {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(names);
+        data.extend_from_slice(&bools);
+        if data.len() % 2 == 1 {
+            data.push(0);
+        }
+        for &number in numbers {
+            if magic == MAGIC_LEGACY {
+                data.extend_from_slice(&(number as i16).to_le_bytes());
+            } else {
+                data.extend_from_slice(&number.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    fn numbers_with_colors(colors: i32) -> Vec<i32> {
+        let mut numbers = vec![-1; COLORS_INDEX + 1];
+        numbers[0] = 80;
+        numbers[COLORS_INDEX] = colors;
+        numbers
+    }
+
+    #[test]
+    fn legacy() {
+        assert_eq!(parse_max_colors(&entry(MAGIC_LEGACY, &numbers_with_colors(256))), Some(256));
+        assert_eq!(parse_max_colors(&entry(MAGIC_LEGACY, &numbers_with_colors(8))), Some(8));
+    }
+
+    #[test]
+    fn extended_32_bit() {
+        let data = entry(MAGIC_32_BIT, &numbers_with_colors(0x100_0000));
+        assert_eq!(parse_max_colors(&data), Some(0x100_0000));
+    }
+
+    #[test]
+    fn absent() {
+        // Absent and cancelled capabilities are stored as negative numbers.
+        assert_eq!(parse_max_colors(&entry(MAGIC_LEGACY, &numbers_with_colors(-1))), None);
+        assert_eq!(parse_max_colors(&entry(MAGIC_32_BIT, &numbers_with_colors(-2))), None);
+        // A numbers section which stops before `colors`.
+        assert_eq!(parse_max_colors(&entry(MAGIC_LEGACY, &[80, -1, 24])), None);
+    }
+
+    #[test]
+    fn garbage() {
+        let data = entry(MAGIC_32_BIT, &numbers_with_colors(256));
+        for len in 0..data.len() {
+            assert_eq!(parse_max_colors(&data[..len]), None, "truncated to {} bytes", len);
+        }
+        assert_eq!(parse_max_colors(b"\x1b[?1049h some text that isn't terminfo"), None);
+        let mut data = entry(MAGIC_LEGACY, &numbers_with_colors(256));
+        data[0] ^= 0xff;
+        assert_eq!(parse_max_colors(&data), None);
+        // A header which claims more names than there are bytes.
+        let mut data = entry(MAGIC_LEGACY, &numbers_with_colors(256));
+        data[2..4].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(parse_max_colors(&data), None);
+    }
+}
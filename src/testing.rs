@@ -0,0 +1,387 @@
+//! Headless testing of widgets.
+//!
+//! A `Harness` polls a widget and renders it to an in-memory surface rather than the terminal.
+//! Input events are fed to the widget by hand and time is simulated using tokio's paused clock,
+//! so tests run deterministically and instantly. The rendered grid can then be checked with
+//! `Harness::assert_text` or compared against a golden file with `Harness::assert_snapshot`.
+//!
+//! This module requires the `testing` feature.
+use super::*;
+
+use std::path::Path;
+use futures::future;
+use tokio::time;
+
+use crate::driver::Driver;
+use crate::graphics::{Color, ColorCode, Style, Surface, SurfaceRef, UnderlineKind};
+use crate::input::Event;
+use crate::widget::Widget;
+
+/// Environment variable which, when set to `1`, makes `Harness::assert_snapshot` rewrite golden
+/// files instead of comparing against them.
+pub const UPDATE_SNAPSHOTS_VAR: &str = "TERMCANDY_UPDATE_SNAPSHOTS";
+
+/// A step of a test script.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Send an input event to the widget.
+    Input(Event),
+    /// Advance the clock by the given amount.
+    Advance(Duration),
+}
+
+/// Drives a widget against an in-memory surface.
+pub struct Harness<W: Widget> {
+    driver: Driver<W>,
+    output_opt: Option<W::Output>,
+    surface: Surface,
+}
+
+impl<W: Widget> Harness<W> {
+    /// Create a harness which renders the widget to a surface of the given size.
+    ///
+    /// This pauses tokio's clock, so it has to be called from inside a single-threaded tokio
+    /// runtime (eg. in a `#[tokio::test]`). Tokio panics if the clock is already paused, eg. by
+    /// another harness in the same test. Use `on_paused_clock` in that case. The widget isn't
+    /// polled until the first step of the test.
+    pub fn new(widget: W, w: u16, h: u16) -> Harness<W> {
+        time::pause();
+        Harness::on_paused_clock(widget, w, h)
+    }
+
+    /// Like `new`, but for when tokio's clock has already been paused.
+    pub fn on_paused_clock(widget: W, w: u16, h: u16) -> Harness<W> {
+        Harness {
+            driver: Driver::new(widget, w, h),
+            output_opt: None,
+            surface: Surface::blank(w, h),
+        }
+    }
+
+    /// Send an input event to the widget and let it react.
+    pub async fn input(&mut self, event: Event) {
+        self.driver.feed_event(event);
+        self.advance(Duration::from_secs(0)).await
+    }
+
+    /// Resize the surface and let the widget react.
+    pub async fn resize(&mut self, w: u16, h: u16) {
+        self.driver.set_size(w, h);
+        self.surface = Surface::blank(w, h);
+        self.advance(Duration::from_secs(0)).await
+    }
+
+    /// Advance the clock by the given duration, letting the widget react to any timers which
+    /// expire along the way.
+    pub async fn advance(&mut self, duration: Duration) {
+        let sleep = time::sleep(duration);
+        pin_utils::pin_mut!(sleep);
+        // While the clock is paused, tokio jumps it forward to the next pending timer whenever the
+        // runtime goes idle. Our own timer stops it from jumping past the end of the duration.
+        future::poll_fn(|cx| {
+            if self.poll(cx).is_ready() {
+                return Poll::Ready(());
+            }
+            sleep.as_mut().poll(cx)
+        }).await;
+        self.render();
+    }
+
+    /// Play a script of input events and clock advances.
+    pub async fn play<S>(&mut self, script: S)
+    where
+        S: IntoIterator<Item = Step>,
+    {
+        for step in script {
+            match step {
+                Step::Input(event) => self.input(event).await,
+                Step::Advance(duration) => self.advance(duration).await,
+            }
+        }
+    }
+
+    /// The widget's output, if it has completed.
+    pub fn output(&self) -> Option<&W::Output> {
+        self.output_opt.as_ref()
+    }
+
+    /// Consume the harness, returning the widget's output if it has completed.
+    pub fn into_output(self) -> Option<W::Output> {
+        self.output_opt
+    }
+
+    /// The surface the widget was last rendered to.
+    ///
+    /// Once the widget completes, the surface keeps the last frame it drew.
+    pub fn surface(&self) -> SurfaceRef {
+        self.surface.as_ref()
+    }
+
+    /// The text of the rendered surface, one line per row, with trailing whitespace removed.
+    pub fn text(&self) -> String {
+        let mut lines = Vec::with_capacity(self.surface.height() as usize);
+        for y in 0..self.surface.height() {
+            let mut line = String::with_capacity(self.surface.width() as usize);
+            for x in 0..self.surface.width() {
+                let c = self.surface.cell(x, y).c;
+                // The cells covered by a wide character hold '\0'.
+                if c != '\0' {
+                    line.push_str(c.as_str());
+                }
+            }
+            lines.push(line.trim_end().to_owned());
+        }
+        lines.join("\n")
+    }
+
+    /// A textual snapshot of the rendered surface: its text, followed by a list of the runs of
+    /// styled cells.
+    pub fn snapshot(&self) -> String {
+        let mut snapshot = self.text();
+        snapshot.push_str("\n--\n");
+        for y in 0..self.surface.height() {
+            let mut x = 0;
+            while x < self.surface.width() {
+                let style = self.surface.cell(x, y).style;
+                let x0 = x;
+                while x < self.surface.width() && self.surface.cell(x, y).style == style {
+                    x += 1;
+                }
+                if style != Style::default() {
+                    snapshot.push_str(&format!("{} {}..{} {}\n", y, x0, x, describe_style(style)));
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// Assert that the text of the rendered surface (as returned by `text`) is equal to
+    /// `expected`. Trailing whitespace on each line of `expected` is ignored.
+    pub fn assert_text(&self, expected: &str) {
+        let expected = expected.lines().map(|line| line.trim_end()).collect::<Vec<_>>().join("\n");
+        let text = self.text();
+        let text = text.trim_end_matches('\n');
+        let expected = expected.trim_end_matches('\n');
+        if text != expected {
+            panic!("rendered text does not match.\n\nexpected:\n{}\n\ngot:\n{}\n", expected, text);
+        }
+    }
+
+    /// Compare a snapshot of the rendered surface against a golden file.
+    ///
+    /// If the file doesn't exist, or the `TERMCANDY_UPDATE_SNAPSHOTS` environment variable is set
+    /// to `1`, the file is (re-)written instead.
+    pub fn assert_snapshot<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref();
+        let snapshot = self.snapshot();
+        let update = env::var(UPDATE_SNAPSHOTS_VAR).map(|s| s == "1").unwrap_or(false);
+        if update || !path.exists() {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).unwrap();
+            }
+            std::fs::write(path, &snapshot).unwrap();
+            return;
+        }
+        let golden = std::fs::read_to_string(path).unwrap();
+        if golden != snapshot {
+            panic!(
+                "snapshot does not match {}. Set {}=1 to update it.\n\nexpected:\n{}\ngot:\n{}",
+                path.display(), UPDATE_SNAPSHOTS_VAR, golden, snapshot,
+            );
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.output_opt.is_some() {
+            return Poll::Ready(());
+        }
+        match self.driver.poll(cx) {
+            Poll::Ready(output) => {
+                self.output_opt = Some(output);
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn render(&mut self) {
+        if self.output_opt.is_some() {
+            return;
+        }
+        // There's no terminal to repaint, but invalidations only last for the frame they're made
+        // in.
+        self.surface.take_invalid();
+        self.driver.render(&mut self.surface.as_mut());
+    }
+}
+
+fn describe_style(style: Style) -> String {
+    let mut parts = Vec::new();
+    if style.fg != Color::Default {
+        parts.push(format!("fg={}", describe_color(style.fg)));
+    }
+    if style.bg != Color::Default {
+        parts.push(format!("bg={}", describe_color(style.bg)));
+    }
+    let attrs = style.attrs;
+    if attrs.bold {
+        parts.push(String::from("bold"));
+    }
+    if attrs.faint {
+        parts.push(String::from("faint"));
+    }
+    if attrs.italic {
+        parts.push(String::from("italic"));
+    }
+    if let Some(underline) = attrs.underline {
+        let kind = match underline.kind {
+            UnderlineKind::Single => "single",
+            UnderlineKind::Double => "double",
+            UnderlineKind::Wavy => "wavy",
+        };
+        match underline.color {
+            Color::Default => parts.push(format!("underline={}", kind)),
+            color => parts.push(format!("underline={}:{}", kind, describe_color(color))),
+        }
+    }
+    if attrs.blink {
+        parts.push(String::from("blink"));
+    }
+    if attrs.strikethrough {
+        parts.push(String::from("strikethrough"));
+    }
+    if attrs.overlined {
+        parts.push(String::from("overlined"));
+    }
+    if let Some(link) = style.link {
+        parts.push(format!("link={}", link.url()));
+    }
+    parts.join(" ")
+}
+
+fn describe_color(color: Color) -> String {
+    match color {
+        Color::Default => String::from("default"),
+        Color::Colors16 { code, bright } => {
+            let name = match code {
+                ColorCode::Black => "black",
+                ColorCode::Red => "red",
+                ColorCode::Green => "green",
+                ColorCode::Yellow => "yellow",
+                ColorCode::Blue => "blue",
+                ColorCode::Magenta => "magenta",
+                ColorCode::Cyan => "cyan",
+                ColorCode::White => "white",
+            };
+            if bright {
+                format!("bright-{}", name)
+            } else {
+                String::from(name)
+            }
+        },
+        Color::Colors256(x) => format!("256:{}", x),
+        Color::Rgb { r, g, b } => format!("#{:02x}{:02x}{:02x}", r, g, b),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use termion::event::Key;
+
+    use crate::graphics::Rect;
+    use crate::input;
+    use crate::widget::FutureExt;
+
+    fn waiting_widget() -> impl Widget<Output = u32> {
+        async {
+            time::sleep(Duration::from_secs(5)).await;
+            input::key(Key::Char('q')).await;
+            7
+        }.draw_as(|surface| surface.print("waiting", 1, 0, Style::default()))
+    }
+
+    #[tokio::test]
+    async fn drives_widget_with_script() {
+        let mut harness = Harness::new(waiting_widget(), 10, 2);
+        harness.advance(Duration::from_secs(0)).await;
+        harness.assert_text(" waiting\n");
+
+        // Input sent before the timer fires is missed.
+        harness.play(vec![
+            Step::Input(Event::Key(Key::Char('q'))),
+            Step::Advance(Duration::from_secs(5)),
+        ]).await;
+        assert_eq!(harness.output(), None);
+
+        harness.input(Event::Key(Key::Char('q'))).await;
+        assert_eq!(harness.into_output(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn clock_already_paused() {
+        let mut first = Harness::new(waiting_widget(), 10, 2);
+        let mut second = Harness::on_paused_clock(waiting_widget(), 10, 2);
+        first.advance(Duration::from_secs(5)).await;
+        second.advance(Duration::from_secs(5)).await;
+        second.input(Event::Key(Key::Char('q'))).await;
+        assert_eq!(first.output(), None);
+        assert_eq!(second.output(), Some(&7));
+    }
+
+    #[tokio::test]
+    async fn invalidations_are_drained() {
+        let widget = future::pending::<()>().draw_as(|surface| {
+            surface.invalidate(Rect { x0: 0, x1: 1, y0: 0, y1: 1 });
+        });
+        let mut harness = Harness::new(widget, 2, 1);
+        for _ in 0..3 {
+            harness.advance(Duration::from_secs(1)).await;
+        }
+        assert_eq!(harness.surface.take_invalid().len(), 1);
+    }
+
+    /// The name of the counter widget's golden file, in a directory of its own.
+    fn golden_path() -> std::path::PathBuf {
+        let dir = format!("termcandy-snapshots-{}", std::process::id());
+        env::temp_dir().join(dir).join("counter.snap")
+    }
+
+    fn counter(count: u32) -> impl Widget<Output = !> {
+        future::pending().draw_as(move |surface| {
+            let style = Style { fg: Color::red(), .. Style::default() };
+            surface.print(&format!("count: {}", count), 0, 0, Style::default());
+            surface.print(&count.to_string(), 7, 0, style);
+        })
+    }
+
+    #[tokio::test]
+    async fn snapshots() {
+        let path = golden_path();
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+
+        // The first run creates the golden file, along with its directory.
+        let mut harness = Harness::new(counter(1), 9, 2);
+        harness.advance(Duration::from_secs(0)).await;
+        harness.assert_snapshot(&path);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "count: 1\n\n--\n0 7..8 fg=red\n");
+        harness.assert_snapshot(&path);
+
+        let mut harness = Harness::on_paused_clock(counter(2), 9, 2);
+        harness.advance(Duration::from_secs(0)).await;
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| harness.assert_snapshot(&path)));
+        let message = res.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("snapshot does not match"), "{}", message);
+        assert!(message.contains("count: 2"), "{}", message);
+
+        // No other test looks at the variable, so setting it can't upset them.
+        env::set_var(UPDATE_SNAPSHOTS_VAR, "1");
+        harness.assert_snapshot(&path);
+        env::remove_var(UPDATE_SNAPSHOTS_VAR);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), harness.snapshot());
+        harness.assert_snapshot(&path);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 use super::*;
 
-use crate::graphics::{SurfaceMut, Rect};
+use crate::graphics::{Rect, RgbaImage, SurfaceMut};
 use crate::input;
 use termion::event::{MouseEvent, Event};
 
@@ -136,6 +136,32 @@ where
     }
 }
 
+pub struct Image {
+    image: RgbaImage,
+}
+
+/// Create a widget that never completes and draws an image, scaled to fill the area it's given.
+pub fn image(image: RgbaImage) -> Image {
+    Image {
+        image,
+    }
+}
+
+impl Future for Image {
+    type Output = !;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<!> {
+        Poll::Pending
+    }
+}
+
+impl Widget for Image {
+    fn draw<'s, 'm>(&self, surface: &'m mut SurfaceMut<'s>) {
+        let rect = surface.rect();
+        surface.draw_image(rect, &self.image);
+    }
+}
+
 /// Widget created using the `Widget::resize` method.
 #[pin_project]
 pub struct Resize<W, M> {